@@ -0,0 +1,360 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use pin_project_lite::pin_project;
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::request_id::RequestId;
+use crate::routes::extractors::ClientInfo;
+use crate::settings::{AccessField, LogSettings};
+use crate::tls::TlsVersion;
+
+// Log target of JSON access lines; `request_id::format_log` writes them unprefixed.
+pub const TARGET: &str = "access";
+
+const REDACTED: &str = "[redacted]";
+
+// Writes one JSON object per request, with the fields and redaction rules from `[log]`.
+#[derive(Clone)]
+pub struct AccessLog {
+    config: Arc<Config>,
+}
+
+struct Config {
+    fields: Vec<AccessField>,
+    redact_query: Vec<String>,
+    redact_headers: Vec<String>,
+    sink: fn(String),
+}
+
+impl AccessLog {
+    pub fn from_settings(settings: &LogSettings) -> Self {
+        AccessLog {
+            config: Arc::new(Config {
+                fields: settings.access_fields.clone(),
+                redact_query: settings.redact_query.clone(),
+                redact_headers: settings.redact_headers.iter().map(|name| name.to_ascii_lowercase()).collect(),
+                sink: |line| log::info!(target: TARGET, "{}", line),
+            }),
+        }
+    }
+}
+
+impl Config {
+    fn wants(&self, field: AccessField) -> bool {
+        self.fields.contains(&field)
+    }
+
+    fn path(&self, path: &str, query: &str) -> String {
+        if query.is_empty() {
+            return path.to_owned();
+        }
+        let query = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((key, _)) if self.redact_query.iter().any(|name| name == key) => format!("{}={}", key, REDACTED),
+                _ => pair.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{}?{}", path, query)
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Map<String, Value> {
+        let mut map = Map::new();
+        for name in headers.keys() {
+            let value = if self.redact_headers.iter().any(|redacted| redacted == name.as_str()) {
+                String::from(REDACTED)
+            } else {
+                headers
+                    .get_all(name)
+                    .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            map.insert(name.to_string(), Value::from(value));
+        }
+        map
+    }
+}
+
+// What is known about a request once its response head is ready; the line is
+// written when the body has been sent, so latency and size cover the whole response.
+struct Entry {
+    config: Arc<Config>,
+    started: Instant,
+    time: OffsetDateTime,
+    method: String,
+    path: String,
+    headers: Option<Map<String, Value>>,
+    peer: Option<String>,
+    tls_version: Option<&'static str>,
+    pattern: Option<String>,
+    status: u16,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    fn start(config: &Arc<Config>, req: &ServiceRequest) -> Self {
+        Entry {
+            config: Arc::clone(config),
+            started: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+            method: req.method().to_string(),
+            path: config.path(req.path(), req.query_string()),
+            headers: config.wants(AccessField::Headers).then(|| config.headers(req.headers())),
+            peer: ClientInfo::of(req.request()).ip.map(|ip| ip.to_string()),
+            tls_version: req.conn_data::<TlsVersion>().map(|version| version.0),
+            pattern: None,
+            status: 0,
+            request_id: None,
+        }
+    }
+
+    fn write(mut self, bytes: u64) {
+        let mut line = Map::new();
+        for field in &self.config.fields {
+            let value = match field {
+                AccessField::Time => self.time.format(&Rfc3339).ok().into(),
+                AccessField::Method => Value::from(std::mem::take(&mut self.method)),
+                AccessField::Path => Value::from(std::mem::take(&mut self.path)),
+                AccessField::Pattern => self.pattern.take().into(),
+                AccessField::Status => self.status.into(),
+                AccessField::LatencyMs => Value::from(self.started.elapsed().as_secs_f64() * 1000.0),
+                AccessField::Bytes => bytes.into(),
+                AccessField::Peer => self.peer.take().into(),
+                AccessField::TlsVersion => self.tls_version.into(),
+                AccessField::RequestId => self.request_id.take().into(),
+                AccessField::Headers => self.headers.take().map_or(Value::Null, Value::Object),
+            };
+            line.insert(field.name().to_owned(), value);
+        }
+        (self.config.sink)(Value::Object(line).to_string());
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<AccessLogBody<B>>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware {
+            service: Rc::new(service),
+            config: Arc::clone(&self.config),
+        }))
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<Config>,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<AccessLogBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let mut entry = Entry::start(&self.config, &req);
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = match fut.await {
+                Ok(res) => res,
+                Err(err) => {
+                    entry.status = err.as_response_error().status_code().as_u16();
+                    entry.write(0);
+                    return Err(err);
+                }
+            };
+
+            entry.pattern = res.request().match_pattern();
+            entry.status = res.status().as_u16();
+            entry.request_id = res.request().extensions().get::<RequestId>().map(RequestId::to_string);
+            Ok(res.map_body(|_, body| AccessLogBody {
+                body,
+                bytes: 0,
+                entry: Some(entry),
+            }))
+        })
+    }
+}
+
+pin_project! {
+    // Counts the bytes sent and writes the log line when the body is dropped,
+    // whether it finished or the client went away.
+    pub struct AccessLogBody<B> {
+        #[pin]
+        body: B,
+        bytes: u64,
+        entry: Option<Entry>,
+    }
+
+    impl<B> PinnedDrop for AccessLogBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            if let Some(entry) = this.entry.take() {
+                entry.write(*this.bytes);
+            }
+        }
+    }
+}
+
+impl<B: MessageBody> MessageBody for AccessLogBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.project();
+        let next = this.body.poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &next {
+            *this.bytes += chunk.len() as u64;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::{RequestIds, X_REQUEST_ID};
+    use crate::settings::Settings;
+    use actix_web::{test, web, App};
+    use std::cell::RefCell;
+
+    thread_local! {
+        static LINES: RefCell<Vec<Value>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn access_log(configure: impl FnOnce(&mut LogSettings)) -> AccessLog {
+        let mut settings = Settings::default().log;
+        configure(&mut settings);
+        let mut log = AccessLog::from_settings(&settings);
+        Arc::get_mut(&mut log.config).unwrap().sink = |line| {
+            LINES.with(|lines| lines.borrow_mut().push(serde_json::from_str(&line).unwrap()))
+        };
+        log
+    }
+
+    #[actix_web::test]
+    async fn test_json_line_has_configured_fields() {
+        let app = test::init_service(
+            App::new()
+                .wrap(access_log(|_| ()))
+                .wrap(RequestIds)
+                .route("/show/{id}", web::get().to(|| async { "hello" })),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/show/7?page=2&access_token=secret")
+            .insert_header((X_REQUEST_ID, "log-1"))
+            .peer_addr("10.0.0.5:4000".parse().unwrap())
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "hello");
+
+        let line = LINES.with(|lines| lines.borrow_mut().pop()).unwrap();
+        assert_eq!(line["method"], "GET");
+        assert_eq!(line["path"], "/show/7?page=2&access_token=[redacted]");
+        assert_eq!(line["pattern"], "/show/{id}");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 5);
+        assert_eq!(line["peer"], "10.0.0.5");
+        assert_eq!(line["tls_version"], Value::Null);
+        assert_eq!(line["request_id"], "log-1");
+        assert!(line["latency_ms"].is_f64());
+        assert!(line["time"].is_string());
+        assert!(line.get("headers").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_headers_are_redacted() {
+        let app = test::init_service(
+            App::new()
+                .wrap(access_log(|settings| {
+                    settings.access_fields = vec![AccessField::Status, AccessField::Headers];
+                }))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Authorization", "Bearer secret"))
+            .insert_header(("Accept", "text/plain"))
+            .to_request();
+        test::call_and_read_body(&app, req).await;
+
+        let line = LINES.with(|lines| lines.borrow_mut().pop()).unwrap();
+        assert_eq!(line.as_object().unwrap().len(), 2);
+        assert_eq!(line["headers"]["authorization"], REDACTED);
+        assert_eq!(line["headers"]["accept"], "text/plain");
+    }
+
+    // Same order as in `main`, where the access log wraps the fallback and problem bodies.
+    #[actix_web::test]
+    async fn test_logs_fallback_responses_as_sent() {
+        use crate::fallback::Fallback;
+        use crate::problem::Problems;
+        use crate::vhost::VirtualHosts;
+        use actix_web::{http, HttpResponse};
+
+        let vhosts = VirtualHosts::from_settings(&Settings::default().vhosts).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(crate::routes::extractors::state(&crate::stats::Stats::default()))
+                .wrap(Fallback::new(vhosts.route_table()))
+                .wrap(Problems)
+                .wrap(access_log(|settings| {
+                    settings.access_fields = vec![AccessField::Status, AccessField::Bytes];
+                }))
+                .wrap(RequestIds)
+                .wrap(vhosts.clone())
+                .default_service(web::to(HttpResponse::NotFound))
+                .configure(|cfg| vhosts.configure(cfg)),
+        )
+        .await;
+
+        for (req, status) in [
+            (test::TestRequest::default().method(http::Method::OPTIONS), http::StatusCode::NO_CONTENT),
+            (test::TestRequest::delete(), http::StatusCode::METHOD_NOT_ALLOWED),
+        ] {
+            let res = test::call_service(&app, req.uri("/hello").to_request()).await;
+            assert_eq!(res.status(), status);
+            let body = test::read_body(res).await;
+
+            let line = LINES.with(|lines| lines.borrow_mut().pop()).unwrap();
+            assert_eq!(line["status"], status.as_u16());
+            assert_eq!(line["bytes"], body.len());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use actix_web::{body, error, HttpResponse};
+use rusqlite::{Connection, OptionalExtension};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::problem::Problem;
+use crate::settings::{CounterSettings, CounterStoreKind};
+
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum StoreError {
+    #[display(fmt = "counter store I/O error: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "counter database error: {}", _0)]
+    Sqlite(rusqlite::Error),
+    #[from(ignore)]
+    #[display(fmt = "invalid counter file {}: {:?}", "_0.display()", _1)]
+    Corrupt(PathBuf, String),
+    #[display(fmt = "counter overflowed")]
+    Overflow,
+}
+
+impl std::error::Error for StoreError {}
+
+// The cause is logged; clients only learn that the counter is unavailable.
+impl error::ResponseError for StoreError {
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        log::error!("{}", self);
+        Problem::new(self.status_code()).with_detail("counter unavailable").response()
+    }
+}
+
+// Where the visit counter is kept. Calls may block on disk I/O, so handlers run
+// them through `request_id::block`.
+pub trait CounterStore: Send + Sync {
+    fn get(&self) -> Result<u64, StoreError>;
+
+    // Adds one and returns the new count.
+    fn increment(&self) -> Result<u64, StoreError>;
+}
+
+pub fn open(settings: &CounterSettings) -> Result<Box<dyn CounterStore>, StoreError> {
+    Ok(match settings.store {
+        CounterStoreKind::Memory => Box::<MemoryStore>::default(),
+        CounterStoreKind::File => Box::new(FileStore::open(&settings.file)?),
+        CounterStoreKind::Sqlite => Box::new(SqliteStore::open(&settings.database)?),
+    })
+}
+
+fn next(count: u64) -> Result<u64, StoreError> {
+    count.checked_add(1).ok_or(StoreError::Overflow)
+}
+
+// Starts from zero on every restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    count: Mutex<u64>,
+}
+
+impl CounterStore for MemoryStore {
+    fn get(&self) -> Result<u64, StoreError> {
+        Ok(*self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    fn increment(&self) -> Result<u64, StoreError> {
+        let mut count = self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *count = next(*count)?;
+        Ok(*count)
+    }
+}
+
+// Keeps the count as decimal text. Every update is written to a temporary file that
+// is then renamed over the old one, so a crash leaves either the old or the new count.
+pub struct FileStore {
+    path: PathBuf,
+    count: Mutex<u64>,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let count = match fs::read_to_string(path) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|_| StoreError::Corrupt(path.to_owned(), text))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(FileStore {
+            path: path.to_owned(),
+            count: Mutex::new(count),
+        })
+    }
+
+    fn write(&self, count: u64) -> io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", count)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl CounterStore for FileStore {
+    fn get(&self) -> Result<u64, StoreError> {
+        Ok(*self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    fn increment(&self) -> Result<u64, StoreError> {
+        let mut count = self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let new = next(*count)?;
+        self.write(new)?;
+        *count = new;
+        Ok(new)
+    }
+}
+
+// Keeps the count in an embedded SQLite database, which other processes can read too.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS counters (name TEXT PRIMARY KEY, value INTEGER NOT NULL)",
+            [],
+        )?;
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+}
+
+impl CounterStore for SqliteStore {
+    fn get(&self) -> Result<u64, StoreError> {
+        let conn = self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count: Option<i64> = conn
+            .query_row("SELECT value FROM counters WHERE name = 'visits'", [], |row| row.get(0))
+            .optional()?;
+        Ok(count.unwrap_or(0) as u64)
+    }
+
+    fn increment(&self) -> Result<u64, StoreError> {
+        let conn = self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count: i64 = conn.query_row(
+            "INSERT INTO counters (name, value) VALUES ('visits', 1)
+             ON CONFLICT (name) DO UPDATE SET value = value + 1
+             RETURNING value",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::testing;
+
+    #[test]
+    fn test_file_store_survives_reopen() {
+        let path = testing::temp_dir("counter-file").join("counter.txt");
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get().unwrap(), 0);
+        assert_eq!(store.increment().unwrap(), 1);
+        assert_eq!(store.increment().unwrap(), 2);
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get().unwrap(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+        assert!(!path.with_extension("txt.tmp").exists());
+
+        fs::write(&path, "many").unwrap();
+        assert!(matches!(FileStore::open(&path), Err(StoreError::Corrupt(..))));
+    }
+
+    #[test]
+    fn test_sqlite_store_survives_reopen() {
+        let path = testing::temp_dir("counter-sqlite").join("counter.db");
+        let _ = fs::remove_file(&path);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.get().unwrap(), 0);
+        assert_eq!(store.increment().unwrap(), 1);
+        assert_eq!(store.increment().unwrap(), 2);
+        drop(store);
+
+        assert_eq!(SqliteStore::open(&path).unwrap().get().unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_counter_endpoint_does_not_count() {
+        use actix_web::{test, App};
+
+        let counter = crate::routes::application::counter(Box::<MemoryStore>::default());
+        let app = test::init_service(App::new().app_data(counter).configure(crate::routes::application_routes)).await;
+
+        let body = test::call_and_read_body(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(body, "Hello Actix Web, Request number: 1");
+        for _ in 0..2 {
+            let count: serde_json::Value =
+                test::call_and_read_body_json(&app, test::TestRequest::get().uri("/counter").to_request()).await;
+            assert_eq!(count["count"], 1);
+        }
+    }
+
+    #[test]
+    fn test_failed_store_calls_are_remembered() {
+        struct Broken;
+
+        impl CounterStore for Broken {
+            fn get(&self) -> Result<u64, StoreError> {
+                Err(StoreError::Overflow)
+            }
+
+            fn increment(&self) -> Result<u64, StoreError> {
+                Err(StoreError::Overflow)
+            }
+        }
+
+        let counter = crate::routes::application::counter(Box::new(Broken));
+        assert_eq!(counter.last().unwrap_err(), "counter overflowed");
+        assert_eq!(counter.record(Ok(7)).unwrap(), 7);
+        assert_eq!(counter.last(), Ok(7));
+        assert!(counter.record(counter.counter.increment()).is_err());
+        assert!(counter.last().is_err());
+    }
+
+    #[test]
+    fn test_memory_store_reports_overflow() {
+        let store = MemoryStore {
+            count: Mutex::new(u64::MAX),
+        };
+        assert!(matches!(store.increment(), Err(StoreError::Overflow)));
+        assert_eq!(store.get().unwrap(), u64::MAX);
+    }
+}
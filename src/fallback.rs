@@ -0,0 +1,351 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, ResourceDef, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::problem::Problem;
+use crate::routes::{extractors::ActiveHost, Route};
+
+const MAX_SUGGESTIONS: usize = 3;
+
+// Registered routes of every virtual host, by host name.
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    hosts: Arc<HashMap<String, Vec<Entry>>>,
+}
+
+struct Entry {
+    pattern: &'static str,
+    methods: &'static [&'static str],
+    def: ResourceDef,
+}
+
+impl RouteTable {
+    pub fn new<'a>(hosts: impl IntoIterator<Item = (String, &'a [Route])>) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(name, routes)| {
+                let entries = routes
+                    .iter()
+                    .map(|&(pattern, methods)| Entry {
+                        pattern,
+                        methods,
+                        def: ResourceDef::new(pattern),
+                    })
+                    .collect();
+                (name, entries)
+            })
+            .collect();
+        RouteTable { hosts: Arc::new(hosts) }
+    }
+
+    fn routes(&self, host: Option<&ActiveHost>) -> &[Entry] {
+        host.and_then(|host| self.hosts.get(&host.name)).map_or(&[], Vec::as_slice)
+    }
+
+    // Methods of every route matching `path`, plus OPTIONS; `None` when no route does.
+    pub fn allowed(&self, host: Option<&ActiveHost>, path: &str) -> Option<Vec<&'static str>> {
+        let mut methods: Vec<&str> = Vec::new();
+        for entry in self.routes(host).iter().filter(|entry| entry.def.is_match(path)) {
+            for method in entry.methods {
+                if !methods.contains(method) {
+                    methods.push(method);
+                }
+            }
+        }
+        if methods.is_empty() {
+            return None;
+        }
+        methods.push("OPTIONS");
+        Some(methods)
+    }
+
+    // Closest registered patterns by edit distance, parameters standing in for the
+    // segment at their position.
+    pub fn suggest(&self, host: Option<&ActiveHost>, path: &str) -> Vec<&'static str> {
+        let mut scored: Vec<(usize, &'static str)> = self
+            .routes(host)
+            .iter()
+            .map(|entry| (distance(path, &fill_params(entry.pattern, path)), entry.pattern))
+            .filter(|&(distance, pattern)| distance <= (pattern.len() / 4).max(2))
+            .collect();
+        scored.sort();
+        scored.dedup_by_key(|(_, pattern)| *pattern);
+        scored.into_iter().take(MAX_SUGGESTIONS).map(|(_, pattern)| pattern).collect()
+    }
+}
+
+fn fill_params(pattern: &str, path: &str) -> String {
+    let mut segments = path.split('/');
+    pattern
+        .split('/')
+        .map(|segment| {
+            let actual = segments.next().unwrap_or("");
+            if segment.starts_with('{') { actual } else { segment }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Levenshtein distance over bytes; paths are ASCII in practice.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substitute.min(previous + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Answers for requests the router couldn't place, recognised as 404 and 405
+// responses without a body: the default service's and those of resources whose
+// routes don't take the method. Known paths answer OPTIONS with their methods and
+// other methods with a 405 and `Allow`; unknown paths get a 404 suggesting similar
+// routes. Handlers' own 404s carry a body and pass through.
+//
+// Wrap it inside `problem::Problems` so its answers are negotiated.
+#[derive(Clone)]
+pub struct Fallback {
+    table: RouteTable,
+}
+
+impl Fallback {
+    pub fn new(table: RouteTable) -> Self {
+        Fallback { table }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Fallback
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = FallbackMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FallbackMiddleware {
+            service: Rc::new(service),
+            table: self.table.clone(),
+        }))
+    }
+}
+
+pub struct FallbackMiddleware<S> {
+    service: Rc<S>,
+    table: RouteTable,
+}
+
+impl<S, B> Service<ServiceRequest> for FallbackMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let table = self.table.clone();
+        Box::pin(async move {
+            let res = service.call(req).await?;
+            let unrouted = matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED)
+                && res.response().body().size().is_eof();
+            if !unrouted {
+                return Ok(res.map_into_left_body());
+            }
+
+            let host = res.request().extensions().get::<ActiveHost>().cloned();
+            let path = res.request().path().to_owned();
+            let method = res.request().method().clone();
+            let answer = match table.allowed(host.as_ref(), &path) {
+                Some(allowed) if method == Method::OPTIONS => HttpResponse::NoContent()
+                    .insert_header((header::ALLOW, allowed.join(", ")))
+                    .finish(),
+                Some(allowed) if !allowed.contains(&method.as_str()) => {
+                    let mut res = Problem::new(StatusCode::METHOD_NOT_ALLOWED)
+                        .with_detail(format!("{} is not allowed on {}", method, path))
+                        .with_extension("allow", &allowed)
+                        .response();
+                    res.headers_mut()
+                        .insert(header::ALLOW, header::HeaderValue::from_str(&allowed.join(", ")).unwrap());
+                    res
+                }
+                // The method is registered but a guard rejected the request.
+                Some(_) => return Ok(res.map_into_left_body()),
+                None => {
+                    let suggestions = table.suggest(host.as_ref(), &path);
+                    let mut problem = Problem::new(StatusCode::NOT_FOUND).with_detail(format!("no route matches {}", path));
+                    if !suggestions.is_empty() {
+                        problem = problem.with_extension("suggestions", suggestions);
+                    }
+                    problem.response()
+                }
+            };
+            Ok(res.into_response(answer).map_into_right_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::problem::Problems;
+    use crate::routes::{self, extractors::PeerIdentity};
+    use crate::settings::Settings;
+    use crate::stats::Stats;
+    use crate::vhost::VirtualHosts;
+    use actix_web::dev::Service;
+    use actix_web::{test, web, App, HttpRequest};
+    use serde_json::{json, Value};
+
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_distance() {
+        assert_eq!(distance("/helo", "/hello"), 1);
+        assert_eq!(distance("/users/7", "/users/7"), 0);
+        assert_eq!(fill_params("/users/{id}", "/user/7"), "/users/7");
+    }
+
+    #[actix_web::test]
+    async fn test_not_found_method_not_allowed_and_options() {
+        let vhosts = VirtualHosts::from_settings(&Settings::default().vhosts).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(routes::extractors::state(&Stats::default()))
+                .wrap(Fallback::new(vhosts.route_table()))
+                .wrap(Problems)
+                .wrap(vhosts.clone())
+                .configure(|cfg| vhosts.configure(cfg))
+                .default_service(web::to(HttpResponse::NotFound)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/helo").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["suggestions"], json!(["/hello", "/hey"]));
+
+        let req = test::TestRequest::delete().uri("/hello").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers().get(header::ALLOW).unwrap(), "GET, OPTIONS");
+
+        // A resource-level 405 gets the header too.
+        let req = test::TestRequest::patch().uri("/users/1").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers().get(header::ALLOW).unwrap(), "GET, PUT, DELETE, OPTIONS");
+
+        let req = test::TestRequest::default().method(Method::OPTIONS).uri("/users").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get(header::ALLOW).unwrap(), "GET, POST, OPTIONS");
+
+        let req = test::TestRequest::default().method(Method::OPTIONS).uri("/nowhere").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    // The route tables have to say what each group registers: every pattern is a
+    // resource of the group, and a method reaches a handler exactly when the table
+    // lists it for a matching pattern. Guarded resources count as reachable when some
+    // request passes the guards.
+    #[actix_web::test]
+    async fn test_route_tables_match_registrations() {
+        async fn check(req: HttpRequest) -> HttpResponse {
+            for (group, _, routes) in routes::GROUPS {
+                for (pattern, _) in routes.iter() {
+                    let path = pattern.replace(['{', '}'], "");
+                    assert!(req.resource_map().has_resource(&path), "{} does not register {}", group, pattern);
+                }
+            }
+            HttpResponse::Ok().finish()
+        }
+
+        let mut app = App::new().route("/__check", web::get().to(check));
+        for (_, routes, _) in routes::GROUPS {
+            app = app.configure(*routes);
+        }
+        let app = test::init_service(app).await;
+        let req = test::TestRequest::get().uri("/__check").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let identity = PeerIdentity {
+            common_name: None,
+            subject_alt_names: Vec::new(),
+            fingerprint: String::new(),
+        };
+        for (group, configure, table) in routes::GROUPS {
+            let identity = identity.clone();
+            let app = test::init_service(
+                App::new()
+                    .wrap_fn(move |req, srv| {
+                        req.extensions_mut().insert(identity.clone());
+                        srv.call(req)
+                    })
+                    .configure(*configure),
+            )
+            .await;
+
+            for (pattern, _) in table.iter() {
+                let path = fill_params(pattern, &pattern.replace(|c| c != '/', "1"));
+                let listed: Vec<&str> = table
+                    .iter()
+                    .filter(|(pattern, _)| ResourceDef::new(*pattern).is_match(&path))
+                    .flat_map(|(_, methods)| methods.iter().copied())
+                    .collect();
+
+                for method in routes::ANY {
+                    let mut routed = false;
+                    for content_type in [None, Some("application/json"), Some("text/plain")] {
+                        let mut req = test::TestRequest::default()
+                            .method(Method::from_bytes(method.as_bytes()).unwrap())
+                            .uri(&path);
+                        if let Some(content_type) = content_type {
+                            req = req.insert_header((header::CONTENT_TYPE, content_type));
+                        }
+                        // The router answers at once; a request still running reached a handler.
+                        let res = test::call_service(&app, req.to_request());
+                        routed = match tokio::time::timeout(Duration::from_millis(100), res).await {
+                            Ok(res) => {
+                                !(matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED)
+                                    && res.response().body().size().is_eof())
+                            }
+                            Err(_) => true,
+                        };
+                        if routed {
+                            break;
+                        }
+                    }
+                    assert_eq!(
+                        routed,
+                        listed.contains(method),
+                        "{}: {} {} does not match the route table",
+                        group,
+                        method,
+                        pattern
+                    );
+                }
+            }
+        }
+    }
+}
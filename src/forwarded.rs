@@ -0,0 +1,310 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::problem::Problem;
+use crate::proxy_protocol::ProxiedPeers;
+use crate::routes::extractors::{ClientInfo, PeerCredentials};
+use crate::settings::{Settings, TrustedProxy};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// Resolves the client's address and scheme from the PROXY protocol and from the
+// forwarding headers of trusted proxies, stores it as `ClientInfo`, and replaces the
+// forwarding headers with a single `Forwarded` header describing the result. Actix
+// reads that header for `connection_info()`, so `Logger`'s `%a` and the absolute
+// URLs built by `url_for` agree with `ClientInfo`, and clients can't spoof either.
+#[derive(Clone)]
+pub struct Forwarded {
+    config: Arc<Config>,
+}
+
+struct Config {
+    trusted: Vec<TrustedProxy>,
+    peers: ProxiedPeers,
+}
+
+// What a trusted proxy chain says about the client.
+#[derive(Debug, PartialEq, Eq)]
+struct Resolved {
+    client: ClientInfo,
+    host: Option<String>,
+}
+
+impl Forwarded {
+    pub fn new(settings: &Settings, peers: ProxiedPeers) -> Self {
+        Forwarded {
+            config: Arc::new(Config {
+                trusted: settings.server.trusted_proxies(),
+                peers,
+            }),
+        }
+    }
+}
+
+impl Config {
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|proxy| match proxy {
+            TrustedProxy::Net(net) => net.contains(&ip),
+            TrustedProxy::Unix => false,
+        })
+    }
+
+    fn resolve(&self, peer: Option<IpAddr>, unix: bool, secure: bool, headers: &HeaderMap) -> Resolved {
+        let scheme = if secure { "https" } else { "http" };
+        let trusted = match peer {
+            _ if unix => self.trusted.contains(&TrustedProxy::Unix),
+            Some(ip) => self.trusts(ip),
+            None => false,
+        };
+        if !trusted {
+            return Resolved {
+                client: ClientInfo { ip: peer, scheme },
+                host: None,
+            };
+        }
+
+        let chain = Chain::from_headers(headers);
+        // Walk back from the nearest proxy; the first address we don't trust is the client.
+        let mut ip = peer;
+        for hop in chain.hops.iter().rev() {
+            ip = *hop;
+            match hop {
+                Some(hop) if self.trusts(*hop) => continue,
+                _ => break,
+            }
+        }
+        let scheme = match chain.proto.as_deref() {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+            Some(proto) if proto.eq_ignore_ascii_case("http") => "http",
+            _ => scheme,
+        };
+
+        Resolved {
+            client: ClientInfo { ip, scheme },
+            host: chain.host,
+        }
+    }
+}
+
+// The `for` addresses, nearest proxy last, and the scheme and host reported by the nearest proxy.
+#[derive(Debug, Default)]
+struct Chain {
+    hops: Vec<Option<IpAddr>>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl Chain {
+    // `Forwarded` wins over the `X-Forwarded-*` family when both are present.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut chain = Chain::default();
+        if headers.contains_key(header::FORWARDED) {
+            for element in values(headers, &header::FORWARDED) {
+                for pair in element.split(';') {
+                    let Some((name, value)) = pair.split_once('=') else { continue };
+                    let value = value.trim().trim_matches('"');
+                    match name.trim().to_ascii_lowercase().as_str() {
+                        "for" => chain.hops.push(parse_hop(value)),
+                        "proto" => chain.proto = Some(value.to_owned()),
+                        "host" => chain.host = Some(value.to_owned()),
+                        _ => {}
+                    }
+                }
+            }
+        } else {
+            chain.hops = values(headers, &X_FORWARDED_FOR).map(parse_hop).collect();
+            chain.proto = values(headers, &X_FORWARDED_PROTO).last().map(String::from);
+            chain.host = values(headers, &X_FORWARDED_HOST).last().map(String::from);
+        }
+        chain
+    }
+}
+
+// Comma separated list items across all instances of a header.
+fn values<'a>(headers: &'a HeaderMap, name: &'a HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+// Accepts `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1` and `[2001:db8::1]:80`. Obfuscated
+// identifiers and `unknown` give `None`.
+fn parse_hop(value: &str) -> Option<IpAddr> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| value.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+fn forwarded_header(resolved: &Resolved) -> Option<HeaderValue> {
+    let mut parts = Vec::new();
+    match resolved.client.ip {
+        Some(IpAddr::V4(ip)) => parts.push(format!("for={}", ip)),
+        Some(IpAddr::V6(ip)) => parts.push(format!("for=\"[{}]\"", ip)),
+        None => {}
+    }
+    parts.push(format!("proto={}", resolved.client.scheme));
+    if let Some(host) = &resolved.host {
+        parts.push(format!("host=\"{}\"", host.replace(['"', '\\'], "")));
+    }
+    HeaderValue::from_str(&parts.join(";")).ok()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Forwarded
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ForwardedMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ForwardedMiddleware {
+            service: Rc::new(service),
+            config: Arc::clone(&self.config),
+        }))
+    }
+}
+
+pub struct ForwardedMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<Config>,
+}
+
+impl<S, B> Service<ServiceRequest> for ForwardedMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let source = req.peer_addr().and_then(|peer| self.config.peers.source(peer));
+        // Only the relay may use its loopback listener; anything else connecting there
+        // would skip the PROXY header and pass for a local client.
+        if source.is_none() && self.config.peers.is_relay(req.app_config().local_addr()) {
+            let problem =
+                Problem::new(StatusCode::FORBIDDEN).with_detail("connection did not come through the PROXY protocol relay");
+            return Box::pin(ready(Err(problem.into())));
+        }
+        let peer = source.or_else(|| req.peer_addr()).map(|peer| peer.ip());
+        let unix = req.conn_data::<PeerCredentials>().is_some();
+        let resolved = self
+            .config
+            .resolve(peer, unix, req.app_config().secure(), req.headers());
+
+        let headers = req.headers_mut();
+        for name in [header::FORWARDED, X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST] {
+            headers.remove(name);
+        }
+        if let Some(value) = forwarded_header(&resolved) {
+            headers.insert(header::FORWARDED, value);
+        }
+        req.extensions_mut().insert(resolved.client);
+
+        Box::pin(self.service.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use actix_web::{test, App};
+
+    fn forwarded(trusted: &str) -> Forwarded {
+        let mut settings = Settings::default();
+        settings.set("server.trusted_proxies", trusted).unwrap();
+        Forwarded::new(&settings, ProxiedPeers::default())
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[actix_web::test]
+    async fn test_only_trusted_proxies_are_believed() {
+        let config = forwarded("10.0.0.0/8").config;
+        let chain = headers(&[("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2"), ("x-forwarded-proto", "https")]);
+
+        let resolved = config.resolve(Some("10.0.0.1".parse().unwrap()), false, false, &chain);
+        assert_eq!(resolved.client.ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(resolved.client.scheme, "https");
+
+        let resolved = config.resolve(Some("192.0.2.9".parse().unwrap()), false, false, &chain);
+        assert_eq!(resolved.client.ip, Some("192.0.2.9".parse().unwrap()));
+        assert_eq!(resolved.client.scheme, "http");
+
+        let rfc = headers(&[("forwarded", r#"for=203.0.113.7;proto=https, for="[2001:db8::1]:443";host=example.com"#)]);
+        let resolved = config.resolve(Some("10.0.0.1".parse().unwrap()), false, false, &rfc);
+        assert_eq!(resolved.client.ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(resolved.host.as_deref(), Some("example.com"));
+
+        let unix = forwarded("unix").config;
+        let resolved = unix.resolve(None, true, false, &chain);
+        assert_eq!(resolved.client.ip, Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(config.resolve(None, true, false, &chain).client.ip, None);
+    }
+
+    #[actix_web::test]
+    async fn test_logging_and_url_for_use_resolved_client() {
+        let app = test::init_service(
+            App::new()
+                .wrap(forwarded("10.0.0.0/8"))
+                .configure(routes::extractor_routes)
+                .configure(routes::url_dispatch_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/url-dispatch/generate-resource-url")
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .insert_header(("x-forwarded-host", "shop.example"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(header::LOCATION).unwrap(),
+            "https://shop.example/url-dispatch/generate-resource-urls/1/2/3"
+        );
+        assert_eq!(res.request().connection_info().realip_remote_addr(), Some("203.0.113.7"));
+
+        // the same headers from an untrusted client are dropped
+        let req = test::TestRequest::get()
+            .uri("/client-info")
+            .peer_addr("192.0.2.9:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .insert_header(("x-forwarded-proto", "https"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.request().connection_info().realip_remote_addr(), Some("192.0.2.9"));
+        let client: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(client["ip"], "192.0.2.9");
+        assert_eq!(client["scheme"], "http");
+    }
+}
@@ -0,0 +1,181 @@
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::shutdown::Shutdown;
+use crate::tls::{self, CertReloader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Warn,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn up() -> Self {
+        Check { status: Status::Up, detail: None }
+    }
+
+    pub fn warn(detail: impl Into<String>) -> Self {
+        Check { status: Status::Warn, detail: Some(detail.into()) }
+    }
+
+    pub fn down(detail: impl Into<String>) -> Self {
+        Check { status: Status::Down, detail: Some(detail.into()) }
+    }
+
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+pub trait HealthCheck: Send + Sync + 'static {
+    fn check(&self) -> Check;
+}
+
+impl<F> HealthCheck for F
+where
+    F: Fn() -> Check + Send + Sync + 'static,
+{
+    fn check(&self) -> Check {
+        self()
+    }
+}
+
+// Liveness checks failing means the process should be restarted; readiness checks
+// failing means it should only be taken out of rotation. `/readyz` runs both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Liveness,
+    Readiness,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checks: BTreeMap<String, Check>,
+}
+
+struct Entry {
+    name: String,
+    probe: Probe,
+    check: Arc<dyn HealthCheck>,
+}
+
+// Shared by all workers; checks can be registered before or after the server starts.
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    checks: Arc<RwLock<Vec<Entry>>>,
+}
+
+impl HealthRegistry {
+    // Registering a name twice replaces the earlier check.
+    pub fn register(&self, name: &str, probe: Probe, check: impl HealthCheck) {
+        let mut checks = self.checks.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        checks.retain(|entry| entry.name != name);
+        checks.push(Entry {
+            name: name.to_owned(),
+            probe,
+            check: Arc::new(check),
+        });
+    }
+
+    pub fn report(&self, probe: Probe) -> Report {
+        let checks: Vec<_> = self
+            .checks
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .filter(|entry| probe == Probe::Readiness || entry.probe == Probe::Liveness)
+            .map(|entry| (entry.name.clone(), Arc::clone(&entry.check)))
+            .collect();
+
+        // Run outside the lock so a slow check doesn't block registration.
+        let checks: BTreeMap<_, _> = checks.into_iter().map(|(name, check)| (name, check.check())).collect();
+        let status = checks.values().map(|check| check.status).max().unwrap_or(Status::Up);
+
+        Report { status, checks }
+    }
+}
+
+pub fn not_draining(shutdown: Shutdown) -> impl HealthCheck {
+    move || match shutdown.is_draining() {
+        true => Check::down("graceful shutdown in progress"),
+        false => Check::up(),
+    }
+}
+
+// Warns once the served certificate is within `warn_before` of expiring.
+pub fn certificate_expiry(certs: CertReloader, warn_before: Duration) -> impl HealthCheck {
+    move || {
+        let expires_at = match certs.certificate_der().as_deref().and_then(tls::expires_at) {
+            Some(expires_at) => expires_at,
+            None => return Check::down("cannot read the served certificate"),
+        };
+
+        match expires_at.duration_since(SystemTime::now()) {
+            Err(_) => Check::down("certificate has expired"),
+            Ok(left) if left < warn_before => Check::warn(format!("certificate expires in {}", days(left))),
+            Ok(left) => Check::up().with_detail(format!("certificate expires in {}", days(left))),
+        }
+    }
+}
+
+fn days(duration: Duration) -> String {
+    format!("{} days", duration.as_secs() / 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_report_takes_worst_status() {
+        let registry = HealthRegistry::default();
+        registry.register("alive", Probe::Liveness, Check::up);
+        registry.register("cache", Probe::Readiness, || Check::warn("cold"));
+        registry.register("database", Probe::Readiness, || Check::down("unreachable"));
+
+        let liveness = registry.report(Probe::Liveness);
+        assert_eq!(liveness.status, Status::Up);
+        assert_eq!(liveness.checks.len(), 1);
+
+        let readiness = registry.report(Probe::Readiness);
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.checks.len(), 3);
+        assert_eq!(readiness.checks["cache"].status, Status::Warn);
+
+        registry.register("database", Probe::Readiness, Check::up);
+        assert_eq!(registry.report(Probe::Readiness).status, Status::Warn);
+    }
+
+    #[actix_web::test]
+    async fn test_certificate_expiry() {
+        let dir = tls::testing::temp_dir("health");
+        tls::testing::Pki::new().write_pair(&dir, "localhost");
+        let settings = crate::settings::TlsSettings {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            ..Default::default()
+        };
+        let certs = CertReloader::new(&settings).unwrap();
+
+        let check = certificate_expiry(certs.clone(), Duration::from_secs(14 * 86400)).check();
+        assert_eq!(check.status, Status::Up, "{:?}", check);
+
+        let check = certificate_expiry(certs, Duration::MAX).check();
+        assert_eq!(check.status, Status::Warn, "{:?}", check);
+    }
+}
@@ -0,0 +1,173 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::rc::Rc;
+
+use crate::routes::extractors::{ClientInfo, PeerCredentials};
+use crate::settings::Settings;
+
+// Redirects requests that arrived on a plain HTTP listener to HTTPS and adds
+// Strict-Transport-Security to responses served over TLS.
+#[derive(Clone, Default)]
+pub struct Https {
+    redirect_port: Option<u16>,
+    hsts: Option<HeaderValue>,
+}
+
+impl Https {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let redirect_port = Some(settings.server.https_port()).filter(|_| !settings.server.http_redirect.is_empty());
+        let hsts = match settings.tls.hsts_max_age {
+            0 => None,
+            max_age if settings.tls.hsts_include_subdomains => Some(format!("max-age={}; includeSubDomains", max_age)),
+            max_age => Some(format!("max-age={}", max_age)),
+        };
+
+        Https {
+            redirect_port,
+            hsts: hsts.map(|value| HeaderValue::from_str(&value).unwrap()),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Https
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HttpsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpsMiddleware {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+pub struct HttpsMiddleware<S> {
+    service: Rc<S>,
+    config: Https,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Behind a trusted TLS-terminating proxy the connection itself is plain HTTP.
+        let secure = ClientInfo::of(req.request()).scheme == "https";
+        // Unix socket connections come from a local proxy that has already terminated TLS.
+        let local_proxy = req.conn_data::<PeerCredentials>().is_some();
+
+        if let (false, false, Some(port)) = (secure, local_proxy, self.config.redirect_port) {
+            let location = https_url(&req, port);
+            let res = HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, location))
+                .finish();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let hsts = self.config.hsts.clone().filter(|_| secure);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(hsts) = hsts {
+                res.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+// Uses the Host header rather than `connection_info()`, which would let a client
+// steer the redirect through X-Forwarded-Host.
+fn https_url(req: &ServiceRequest, port: u16) -> String {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or_else(|| req.app_config().host());
+    let host = strip_port(host);
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+
+    match port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[actix_web::test]
+    async fn test_plain_http_is_redirected() {
+        let mut settings = Settings::default();
+        settings.server.http_redirect = vec![String::from("127.0.0.1:8000")];
+        settings.server.bind = vec![String::from("0.0.0.0:8443")];
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Https::from_settings(&settings))
+                .route("/hello", web::get().to(|| async { "hello" })),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/hello?name=a%20b")
+            .insert_header((header::HOST, "example.test:8000"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers().get(header::LOCATION).unwrap(),
+            "https://example.test:8443/hello?name=a%20b"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_disabled_by_default() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Https::from_settings(&Settings::default()))
+                .route("/hello", web::get().to(|| async { "hello" })),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/hello").to_request()).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(header::STRICT_TRANSPORT_SECURITY).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_strip_port() {
+        assert_eq!(strip_port("example.test:8000"), "example.test");
+        assert_eq!(strip_port("example.test"), "example.test");
+        assert_eq!(strip_port("[::1]:8000"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+}
@@ -0,0 +1,171 @@
+// Socket activation, Unix sockets and their peer credentials only exist on unix;
+// elsewhere the server listens on `server.bind` alone.
+use std::{io, net};
+
+#[cfg(unix)]
+use {
+    actix_web::dev::Extensions,
+    std::any::Any,
+    std::os::unix::fs::FileTypeExt,
+    std::os::unix::io::{FromRawFd, IntoRawFd, RawFd},
+    std::os::unix::net::{UnixListener, UnixStream},
+    std::path::Path,
+    std::{env, fs},
+};
+
+#[cfg(unix)]
+use crate::routes::extractors::PeerCredentials;
+
+// First descriptor passed by systemd socket activation; stdin, stdout and stderr come before it.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(not(unix))]
+pub fn from_systemd() -> io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
+
+// Takes over the sockets systemd passed through LISTEN_FDS, if they were meant for
+// this process. The variables are cleared so child processes don't claim them too.
+#[cfg(unix)]
+pub fn from_systemd() -> io::Result<Vec<Listener>> {
+    let fds = listen_fds(env::var("LISTEN_PID").ok(), env::var("LISTEN_FDS").ok(), std::process::id());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // Safety: systemd hands these descriptors to this process (LISTEN_PID matched)
+    // and nothing else in the process owns them.
+    fds.map(|fd| unsafe { listener(fd) }).collect()
+}
+
+#[cfg(unix)]
+fn listen_fds(pid: Option<String>, fds: Option<String>, own_pid: u32) -> std::ops::Range<RawFd> {
+    let count = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid.parse() == Ok(own_pid) => fds.parse().unwrap_or(0),
+        _ => 0,
+    };
+    SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count
+}
+
+#[cfg(unix)]
+unsafe fn listener(fd: RawFd) -> io::Result<Listener> {
+    let unix = UnixListener::from_raw_fd(fd);
+    if unix.local_addr().is_ok() {
+        return Ok(Listener::Unix(unix));
+    }
+
+    let tcp = net::TcpListener::from_raw_fd(unix.into_raw_fd());
+    match tcp.local_addr() {
+        Ok(_) => Ok(Listener::Tcp(tcp)),
+        Err(err) => {
+            // Leave a descriptor we don't understand to whoever passed it.
+            let _ = tcp.into_raw_fd();
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("LISTEN_FDS descriptor {} is not a TCP or Unix socket: {}", fd, err),
+            ))
+        }
+    }
+}
+
+// Binds a Unix domain socket, replacing a socket file left behind by a previous
+// run. A socket that still accepts connections belongs to a live server and is kept.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ))
+            }
+            Err(_) => fs::remove_file(path)?,
+        }
+    }
+    UnixListener::bind(path)
+}
+
+// `HttpServer::on_connect` hook that stores the peer credentials of Unix socket connections.
+#[cfg(unix)]
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<tokio::net::UnixStream>() else {
+        return;
+    };
+    match stream.peer_cred() {
+        Ok(cred) => {
+            data.insert(PeerCredentials {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            });
+        }
+        Err(err) => log::warn!("cannot read Unix socket peer credentials: {}", err),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use crate::tls::testing;
+    use actix_web::{App, HttpServer};
+
+    use std::io::{Read, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_listen_fds_must_match_pid() {
+        let range = |pid: &str, fds: &str| listen_fds(Some(pid.to_owned()), Some(fds.to_owned()), 42);
+        assert_eq!(range("42", "2"), 3..5);
+        assert!(range("41", "2").is_empty());
+        assert!(range("42", "many").is_empty());
+        assert!(listen_fds(None, Some(String::from("2")), 42).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_unix_socket_serves_peer_credentials() {
+        let dir = testing::temp_dir("listen-unix");
+        let path = dir.join("app.sock");
+
+        // a stale socket file from an earlier run is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+        assert_eq!(bind_unix(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let server = HttpServer::new(|| App::new().configure(routes::extractor_routes))
+            .workers(1)
+            .on_connect(on_connect)
+            .listen_uds(listener)
+            .unwrap()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let socket = path.clone();
+        let response = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = UnixStream::connect(socket).unwrap();
+            write!(stream, "GET /peer-credentials HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let credentials: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(credentials["uid"], fs::metadata(&dir).unwrap().uid());
+        assert_eq!(credentials["pid"], std::process::id());
+
+        handle.stop(false).await;
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,45 +1,183 @@
-use actix_web::{http, middleware, web, App, HttpServer};
-use actix_web::middleware::Logger;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_web::{dev::Service, middleware, web, App, HttpResponse, HttpServer};
+use actix_web::middleware::{Condition, Logger};
 
 use std::time::Duration;
 
+mod access_log;
+mod counter;
+mod fallback;
+mod forwarded;
+mod health;
+mod https;
+mod listen;
+mod metrics;
+mod panics;
+mod path_params;
+mod problem;
+mod proxy_protocol;
+mod request_id;
 mod routes;
+mod settings;
+mod shutdown;
+mod stats;
+mod telemetry;
+mod tls;
+mod users;
+mod validation;
+mod vhost;
+
+use settings::{AccessFormat, Settings};
 
 #[rustfmt::skip]
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "info");
+    let settings = Settings::load().unwrap_or_else(|err| exit_with(err));
+
     std::env::set_var("RUST_BACKTRACE", "1");
-    env_logger::init();
+    env_logger::Builder::new()
+        .parse_filters(&settings.log.level)
+        .format(request_id::format_log)
+        .init();
+    let telemetry = telemetry::Telemetry::init(&settings.tracing).unwrap_or_else(|err| exit_with(err));
+
+    let https = https::Https::from_settings(&settings);
+    let shutdown = shutdown::Shutdown::new(&settings);
+    let shutdown_data = web::Data::new(shutdown.clone());
+    let drain = shutdown.clone();
+    let settings_data = web::Data::new(settings.clone());
+    let store = counter::open(&settings.counter).unwrap_or_else(|err| exit_with(err));
+    let counter = routes::application::counter(store);
+    let users = web::Data::from(users::open(&settings.users).unwrap_or_else(|err| exit_with(err)));
+
+    let certs = tls::CertReloader::new(&settings.tls).unwrap_or_else(|err| exit_with(err));
+    certs.clone().spawn_watcher();
+
+    // No liveness checks: the stores recover their locks from a panicking handler, so
+    // nothing in the process can wedge itself, and `/healthz` answering is the signal.
+    let health = health::HealthRegistry::default();
+    let state = counter.clone();
+    health.register("counter_store", health::Probe::Readiness, move || match state.last() {
+        Ok(_) => health::Check::up(),
+        Err(err) => health::Check::down(err),
+    });
+    health.register("shutdown", health::Probe::Readiness, health::not_draining(shutdown.clone()));
+    health.register(
+        "tls_certificate",
+        health::Probe::Readiness,
+        health::certificate_expiry(certs.clone(), Duration::from_secs(14 * 24 * 60 * 60)),
+    );
+    let health_data = web::Data::new(health);
+
+    let stats = stats::Stats::default();
+    let metrics = metrics::Metrics::new();
+    let state = counter.clone();
+    metrics.gauge("app_counter", "Requests counted by AppStateWithCounter", move || {
+        state.last().map_or(f64::NAN, |count| count as f64)
+    });
+    let totals = stats.clone();
+    metrics.gauge("app_global_count", "Requests to /add-one, summed over all workers", move || {
+        totals.total(routes::extractors::ADD_ONE) as f64
+    });
+    let metrics_data = web::Data::new(metrics.clone());
 
-    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    builder
-        .set_private_key_file("key.pem", SslFiletype::PEM)
-        .unwrap();
-    builder.set_certificate_chain_file("cert.pem").unwrap();
+    let json_access_log = settings.log.access_format == AccessFormat::Json;
+    let access_log = access_log::AccessLog::from_settings(&settings.log);
+    let peers = proxy_protocol::ProxiedPeers::default();
+    let forwarded = forwarded::Forwarded::new(&settings, peers.clone());
+    let vhosts = vhost::VirtualHosts::from_settings(&settings.vhosts).unwrap_or_else(|err| exit_with(err));
 
     let app = move || {
         App::new()
-            .wrap(Logger::default())
+            .app_data(shutdown_data.clone())
+            .app_data(settings_data.clone())
+            .app_data(health_data.clone())
+            .app_data(counter.clone())
+            .app_data(users.clone())
+            .app_data(metrics_data.clone())
+            .app_data(web::Data::new(stats.clone()))
+            .app_data(routes::extractors::state(&stats))
+            .wrap(panics::CatchPanic::new(metrics.clone()))
+            .wrap(https.clone())
+            .wrap(drain.clone())
+            // Inside metrics and the access log, so they see the responses that
+            // fallback and problem bodies turn the empty errors into.
+            .wrap(fallback::Fallback::new(vhosts.route_table()))
+            .wrap(problem::Problems)
+            .wrap(metrics.clone())
+            .wrap(Condition::new(json_access_log, access_log.clone()))
+            .wrap(request_id::RequestIds)
+            .wrap(telemetry::Tracing)
+            .wrap(Condition::new(!json_access_log, Logger::new(request_id::ACCESS_LOG_FORMAT)))
             .wrap(middleware::NormalizePath::trim())  // url-dispatch/path-normalization
-            .default_service(web::route().method(http::Method::GET))  // url-dispatch/path-normalization
-            .configure(routes::application_routes)
-            .configure(routes::server_routes)
-            .configure(routes::extractor_routes)
-            .configure(routes::handler_routes)
-            .configure(routes::error_routes)
-            .configure(routes::url_dispatch_routes)
-            .configure(routes::testing_routes)
+            .wrap(vhosts.clone())
+            .wrap(forwarded.clone())
+            .wrap_fn(|req, srv| {
+                routes::extractors::attach_peer_identity(&req);
+                srv.call(req)
+            })
+            .default_service(web::to(HttpResponse::NotFound))  // answered by fallback::Fallback
+            .configure(problem::configure)
+            .configure(|cfg| vhosts.configure(cfg))
     };
 
-    let _one   = HttpServer::new(app).keep_alive(Duration::from_secs(75));
-    let _two   = HttpServer::new(app).keep_alive(http::KeepAlive::Os);
-    let _three = HttpServer::new(app).keep_alive(None);
+    let mut server = HttpServer::new(app)
+        .on_connect(|conn, data| {
+            tls::on_connect(conn, data);
+            #[cfg(unix)]
+            listen::on_connect(conn, data);
+        })
+        .workers(settings.server.workers)
+        .keep_alive(settings.server.keep_alive)
+        .client_request_timeout(Duration::from_secs(settings.server.client_request_timeout))
+        .client_disconnect_timeout(Duration::from_secs(settings.server.client_disconnect_timeout))
+        .max_connections(settings.server.max_connections)
+        .shutdown_timeout(settings.server.shutdown_timeout)
+        .disable_signals();
+
+    // With server.proxy_protocol every TCP listener expects a PROXY header first.
+    let proxy = |listener: std::net::TcpListener| match settings.server.proxy_protocol {
+        true => proxy_protocol::relay(listener, peers.clone(), shutdown.clone()),
+        false => Ok(listener),
+    };
+
+    let inherited = listen::from_systemd()?;
+    if inherited.is_empty() {
+        for addr in &settings.server.bind {
+            let acceptor = certs.acceptor().unwrap_or_else(|err| exit_with(err));
+            server = acceptor.listen(server, proxy(std::net::TcpListener::bind(addr)?)?)?;
+        }
+        #[cfg(unix)]
+        for path in settings.server.unix_sockets() {
+            server = server.listen_uds(listen::bind_unix(path)?)?;
+        }
+    } else {
+        log::info!("serving {} sockets passed by systemd", inherited.len());
+        // TCP sockets serve HTTPS like server.bind, Unix sockets plain HTTP like server.listen.
+        for listener in inherited {
+            server = match listener {
+                listen::Listener::Tcp(listener) => {
+                    let acceptor = certs.acceptor().unwrap_or_else(|err| exit_with(err));
+                    acceptor.listen(server, proxy(listener)?)?
+                }
+                #[cfg(unix)]
+                listen::Listener::Unix(listener) => server.listen_uds(listener)?,
+            };
+        }
+    }
+    for addr in &settings.server.http_redirect {
+        server = server.listen(proxy(std::net::TcpListener::bind(addr)?)?)?;
+    }
+
+    let server = server.run();
+    shutdown.spawn(server.handle());
+    server.await?;
+
+    telemetry.shutdown();
+    log::info!("server stopped");
+    Ok(())
+}
 
-    HttpServer::new(app)
-        .workers(1)
-        .bind_openssl(("127.0.0.1", 8080), builder)?
-        .run()
-        .await
+fn exit_with(err: impl std::fmt::Display) -> ! {
+    eprintln!("error: {err}");
+    std::process::exit(2)
 }
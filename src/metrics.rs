@@ -0,0 +1,239 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::Error;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use prometheus::{Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Label used for requests that matched no route, so that scanners probing random
+// paths can't create unbounded label values.
+const UNMATCHED: &str = "<unmatched>";
+
+// Label for extension methods, which clients can make up just like paths.
+const OTHER_METHOD: &str = "OTHER";
+
+type Sampler = Box<dyn Fn() -> f64 + Send + Sync>;
+
+// Request metrics recorded by the middleware, plus gauges sampled on every scrape.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    in_flight: IntGauge,
+    panics: IntCounter,
+    gauges: Mutex<Vec<(Gauge, Sampler)>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by method, route pattern and status class"),
+            &["method", "pattern", "status"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by method and route pattern"),
+            &["method", "pattern"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new("http_requests_in_flight", "HTTP requests currently being served").unwrap();
+        let panics = IntCounter::new("http_handler_panics_total", "Panics caught while serving requests").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(panics.clone())).unwrap();
+
+        Metrics {
+            inner: Arc::new(Inner {
+                registry,
+                requests,
+                latency,
+                in_flight,
+                panics,
+                gauges: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    // Exports a value owned elsewhere, such as an application counter, as a gauge.
+    pub fn gauge(&self, name: &str, help: &str, sample: impl Fn() -> f64 + Send + Sync + 'static) {
+        let gauge = Gauge::new(name, help).unwrap();
+        self.inner.registry.register(Box::new(gauge.clone())).unwrap();
+        self.inner
+            .gauges
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push((gauge, Box::new(sample)));
+    }
+
+    pub fn record_panic(&self) {
+        self.inner.panics.inc();
+    }
+
+    // Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        for (gauge, sample) in self.inner.gauges.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+            gauge.set(sample());
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.inner.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn observe(&self, method: &str, pattern: &str, status: u16, started: Instant) {
+        let class = format!("{}xx", status / 100);
+        self.inner.requests.with_label_values(&[method, pattern, &class]).inc();
+        self.inner
+            .latency
+            .with_label_values(&[method, pattern])
+            .observe(started.elapsed().as_secs_f64());
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Metrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MetricsMiddleware {
+            service: Rc::new(service),
+            metrics: self.clone(),
+        }))
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let method = method_label(req.method());
+        let metrics = self.metrics.clone();
+        let in_flight = InFlight::start(&metrics.inner.in_flight);
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            drop(in_flight);
+
+            // Routing happens inside the wrapped service, so the pattern is only
+            // known once the response comes back.
+            let (pattern, status) = match &res {
+                Ok(res) => (res.request().match_pattern(), res.status().as_u16()),
+                Err(err) => (None, err.as_response_error().status_code().as_u16()),
+            };
+            metrics.observe(method, pattern.as_deref().unwrap_or(UNMATCHED), status, started);
+            res
+        })
+    }
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => OTHER_METHOD,
+    }
+}
+
+// Decrements the in-flight gauge even if the request future is dropped early.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn start(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        InFlight(gauge.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn test_requests_are_labeled_by_pattern() {
+        let metrics = Metrics::new();
+        metrics.gauge("app_answer", "A sampled value", || 42.0);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(metrics.clone())
+                .route("/show/{id}", web::get().to(|| async { "user" }))
+                .route("/fail", web::get().to(HttpResponse::InternalServerError)),
+        )
+        .await;
+        for uri in ["/show/1", "/show/2", "/fail", "/missing"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let text = metrics.render();
+        assert!(text.contains(r#"http_requests_total{method="GET",pattern="/show/{id}",status="2xx"} 2"#), "{}", text);
+        assert!(text.contains(r#"http_requests_total{method="GET",pattern="/fail",status="5xx"} 1"#), "{}", text);
+        assert!(text.contains(r#"http_requests_total{method="GET",pattern="<unmatched>",status="4xx"} 1"#), "{}", text);
+        assert!(text.contains(r#"http_request_duration_seconds_count{method="GET",pattern="/show/{id}"} 2"#), "{}", text);
+        assert!(text.contains("http_requests_in_flight 0"), "{}", text);
+        assert!(text.contains("app_answer 42"), "{}", text);
+    }
+
+    #[actix_web::test]
+    async fn test_extension_methods_share_a_label() {
+        let metrics = Metrics::new();
+        let app = test::init_service(App::new().wrap(metrics.clone()).route("/", web::to(HttpResponse::Ok))).await;
+        for method in ["FOO1", "FOO2", "PATCH"] {
+            let req = test::TestRequest::default().method(Method::from_bytes(method.as_bytes()).unwrap()).uri("/");
+            test::call_service(&app, req.to_request()).await;
+        }
+
+        let text = metrics.render();
+        assert!(text.contains(r#"http_requests_total{method="OTHER",pattern="/",status="2xx"} 2"#), "{}", text);
+        assert!(text.contains(r#"http_requests_total{method="PATCH",pattern="/",status="2xx"} 1"#), "{}", text);
+        assert!(!text.contains("FOO"), "{}", text);
+    }
+}
@@ -0,0 +1,245 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use pin_project_lite::pin_project;
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, PanicHookInfo};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+use crate::metrics::Metrics;
+use crate::problem::Problem;
+
+thread_local! {
+    // Set while a request future is polled under `CatchPanic`.
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    // What the panic hook saw of the last caught panic on this thread.
+    static CAUGHT: RefCell<Option<Caught>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+struct Caught {
+    message: String,
+    backtrace: Backtrace,
+}
+
+// Turns a panic while serving a request into a 500 problem carrying an incident
+// ID, and logs the panic message and backtrace under that ID instead of letting
+// it take down the connection.
+//
+// The problem is returned as an error, so wrap it inside `problem::Problems` and
+// `RequestIds` to have it negotiated and tagged.
+#[derive(Clone)]
+pub struct CatchPanic {
+    metrics: Metrics,
+}
+
+impl CatchPanic {
+    pub fn new(metrics: Metrics) -> Self {
+        install_hook();
+        CatchPanic { metrics }
+    }
+}
+
+// Panics under `CatchPanic` are recorded for the middleware; any other panic goes
+// to the previous hook as before.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(Cell::get) {
+                return previous(info);
+            }
+            let caught = Caught {
+                message: describe(info),
+                backtrace: Backtrace::force_capture(),
+            };
+            CAUGHT.with(|slot| *slot.borrow_mut() = Some(caught));
+        }));
+    });
+}
+
+fn describe(info: &PanicHookInfo<'_>) -> String {
+    let message = payload_message(info.payload());
+    match info.location() {
+        Some(location) => format!("{} at {}", message, location),
+        None => message,
+    }
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("non-string panic payload")
+    }
+}
+
+pin_project! {
+    struct Catching<F> {
+        #[pin]
+        inner: F,
+    }
+}
+
+// Restores the flag when the poll returns or unwinds.
+struct CatchingScope(bool);
+
+impl CatchingScope {
+    fn enter() -> Self {
+        CatchingScope(CATCHING.with(|flag| flag.replace(true)))
+    }
+}
+
+impl Drop for CatchingScope {
+    fn drop(&mut self) {
+        CATCHING.with(|flag| flag.set(self.0));
+    }
+}
+
+impl<F: Future> Future for Catching<F> {
+    type Output = Result<F::Output, Caught>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = self.project().inner;
+        let scope = CatchingScope::enter();
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| inner.poll(cx)));
+        drop(scope);
+
+        match polled {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => {
+                let caught = CAUGHT.with(|slot| slot.borrow_mut().take()).unwrap_or_else(|| Caught {
+                    message: payload_message(payload.as_ref()),
+                    backtrace: Backtrace::disabled(),
+                });
+                Poll::Ready(Err(caught))
+            }
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CatchPanic
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CatchPanicMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CatchPanicMiddleware {
+            service: Rc::new(service),
+            metrics: self.metrics.clone(),
+        }))
+    }
+}
+
+pub struct CatchPanicMiddleware<S> {
+    service: Rc<S>,
+    metrics: Metrics,
+}
+
+impl<S, B> Service<ServiceRequest> for CatchPanicMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Cloning the request would keep the router from writing match info into
+        // it, so only what the log line needs is kept.
+        let method = req.method().clone();
+        let path = req.path().to_owned();
+        let service = Rc::clone(&self.service);
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let caught = match (Catching { inner: async move { service.call(req).await } }).await {
+                Ok(res) => return res,
+                Err(caught) => caught,
+            };
+
+            let incident = uuid::Uuid::new_v4().to_string();
+            metrics.record_panic();
+            log::error!(
+                "panic while serving {} {} (incident {}): {}\n{}",
+                method,
+                path,
+                incident,
+                caught.message,
+                caught.backtrace
+            );
+
+            // Rendered by the outer middleware, which has the request ID in scope.
+            Err(Problem::new(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_detail("the server hit an unexpected error; quote the incident ID when reporting it")
+                .with_extension("incident_id", &incident)
+                .into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::problem::Problems;
+    use crate::request_id::{RequestIds, X_REQUEST_ID};
+    use crate::routes;
+    use actix_web::body::to_bytes;
+    use actix_web::{test, web, App};
+    use serde_json::Value;
+
+    async fn boom() -> &'static str {
+        panic!("boom")
+    }
+
+    #[actix_web::test]
+    async fn test_panics_become_incidents() {
+        let metrics = Metrics::new();
+        let app = test::init_service(
+            App::new()
+                .wrap(CatchPanic::new(metrics.clone()))
+                .wrap(Problems)
+                .wrap(RequestIds)
+                .route("/boom", web::get().to(boom))
+                .configure(routes::url_dispatch_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/boom").insert_header((X_REQUEST_ID, "abc-123")).to_request();
+        // The server turns the error into a response; the test harness doesn't.
+        let res = test::try_call_service(&app, req).await.unwrap_err().error_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: Value = serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert!(uuid::Uuid::parse_str(body["incident_id"].as_str().unwrap()).is_ok(), "{}", body);
+        assert_eq!(body["request_id"], "abc-123");
+        assert!(metrics.render().contains("http_handler_panics_total 1"));
+
+        // `/url-dispatch/match` used to unwrap its segments; a bad one is now a 400.
+        let req = test::TestRequest::get().uri("/url-dispatch/match/999/1").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        // The worker keeps serving.
+        let req = test::TestRequest::get().uri("/url-dispatch/path/alice/1").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Welcome alice! id: 1");
+    }
+}
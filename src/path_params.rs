@@ -0,0 +1,176 @@
+use actix_web::{body, error, http, Error, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+
+use std::any::type_name;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::problem::Problem;
+
+#[derive(Debug, derive_more::Display)]
+pub enum ParamError {
+    #[display(fmt = "path segment {:?} is missing", _0)]
+    Missing(String),
+    #[display(fmt = "path segment {:?} is {:?}, expected {}", segment, value, expected)]
+    Invalid {
+        segment: String,
+        value: String,
+        expected: &'static str,
+    },
+    #[display(fmt = "path segments do not form {}: {}", expected, reason)]
+    Segments { expected: &'static str, reason: String },
+}
+
+impl std::error::Error for ParamError {}
+
+impl ParamError {
+    pub fn segment(&self) -> Option<&str> {
+        match self {
+            ParamError::Missing(segment) | ParamError::Invalid { segment, .. } => Some(segment),
+            ParamError::Segments { .. } => None,
+        }
+    }
+
+    pub fn expected(&self) -> Option<&'static str> {
+        match self {
+            ParamError::Missing(_) => None,
+            ParamError::Invalid { expected, .. } | ParamError::Segments { expected, .. } => Some(expected),
+        }
+    }
+
+    // The 400 this error answers with; custom handlers can extend it.
+    pub fn problem(&self) -> Problem {
+        let mut problem = Problem::new(http::StatusCode::BAD_REQUEST)
+            .with_type("/problems/invalid-path", "Invalid path parameter")
+            .with_detail(self);
+        if let Some(segment) = self.segment() {
+            problem = problem.with_extension("segment", segment);
+        }
+        if let Some(expected) = self.expected() {
+            problem = problem.with_extension("expected", expected);
+        }
+        problem
+    }
+}
+
+impl error::ResponseError for ParamError {
+    fn status_code(&self) -> http::StatusCode {
+        http::StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        self.problem().response()
+    }
+}
+
+type ErrorHandler = dyn Fn(ParamError, &HttpRequest) -> Error + Send + Sync;
+
+// Like `web::PathConfig` for `PathParams`: register it as app data on an app or
+// scope to turn its parameter errors into something else.
+#[derive(Clone, Default)]
+pub struct ParamConfig {
+    err_handler: Option<Arc<ErrorHandler>>,
+}
+
+impl ParamConfig {
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(ParamError, &HttpRequest) -> Error + Send + Sync + 'static,
+    {
+        self.err_handler = Some(Arc::new(handler));
+        self
+    }
+
+    fn handle(req: &HttpRequest, err: ParamError) -> Error {
+        match req.app_data::<ParamConfig>().and_then(|config| config.err_handler.as_ref()) {
+            Some(handler) => handler(err, req),
+            None => err.into(),
+        }
+    }
+}
+
+// Typed access to the request's match info, answering 400 instead of panicking
+// when a segment is missing or doesn't parse.
+pub trait PathParams {
+    fn param<T: FromStr>(&self, name: &str) -> Result<T, Error>;
+
+    // All segments at once, like `Path::load`.
+    fn params<T: DeserializeOwned>(&self) -> Result<T, Error>;
+}
+
+impl PathParams for HttpRequest {
+    fn param<T: FromStr>(&self, name: &str) -> Result<T, Error> {
+        let value = self
+            .match_info()
+            .get(name)
+            .ok_or_else(|| ParamConfig::handle(self, ParamError::Missing(name.to_owned())))?;
+        value.parse().map_err(|_| {
+            let err = ParamError::Invalid {
+                segment: name.to_owned(),
+                value: value.to_owned(),
+                expected: short_type_name::<T>(),
+            };
+            ParamConfig::handle(self, err)
+        })
+    }
+
+    fn params<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.match_info().load().map_err(|err| {
+            let err = ParamError::Segments {
+                expected: short_type_name::<T>(),
+                reason: err.to_string(),
+            };
+            ParamConfig::handle(self, err)
+        })
+    }
+}
+
+// `u8` rather than `core::primitive::u8`, `String` rather than `alloc::string::String`.
+fn short_type_name<T>() -> &'static str {
+    let name = type_name::<T>();
+    match name.contains('<') || name.starts_with('(') {
+        true => name,
+        false => name.rsplit("::").next().unwrap_or(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use crate::stats::Stats;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn test_bad_segments_are_client_errors() {
+        let app = test::init_service(
+            App::new()
+                .app_data(routes::extractors::state(&Stats::default()))
+                .configure(routes::extractor_routes)
+                .configure(routes::url_dispatch_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/url-dispatch/match/9/1").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Values 9 1 9 1");
+
+        for (uri, segment) in [("/url-dispatch/match/abc/1", "v1"), ("/url-dispatch/match/1/999", "v2")] {
+            let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), http::StatusCode::BAD_REQUEST, "{}", uri);
+            let body: Value = test::read_body_json(res).await;
+            assert_eq!(body["segment"], segment);
+            assert_eq!(body["expected"], "u8");
+            // Set by the url-dispatch scope's handler.
+            assert_eq!(body["pattern"], "/url-dispatch/match/{v1}/{v2}");
+        }
+
+        let req = test::TestRequest::get().uri("/posts/x/alice").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["detail"], r#"path segment "post_id" is "x", expected i32"#);
+        // Outside the url-dispatch scope its handler doesn't apply.
+        assert_eq!(body.get("pattern"), None);
+    }
+}
@@ -0,0 +1,511 @@
+use actix_web::body::{self, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{InternalError, ResponseError};
+use actix_web::http::header::{self, Accept, Header};
+use actix_web::{http, web, Error, HttpRequest, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::request_id::RequestId;
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+// Styled page for browsers; `{{name}}` placeholders are replaced with the escaped
+// members of the problem.
+const HTML_TEMPLATE: &str = include_str!("../static/error.html");
+
+// Marks responses written by `Problem`, so `Problems` can tell them from the
+// plain-text bodies of actix's own errors.
+struct Rendered;
+
+tokio::task_local! {
+    static CONTEXT: Context;
+}
+
+struct Context {
+    instance: Rc<str>,
+    format: Format,
+}
+
+// How an error body is written, picked from the request's `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    ProblemJson,
+    Json,
+    Html,
+    Text,
+}
+
+impl Format {
+    // Takes the first acceptable format by quality. A bare `*/*`, as curl sends it,
+    // gets text; a request without `Accept` is most likely a program and gets
+    // problem+json, as does one accepting nothing we can write.
+    pub fn negotiate(req: &HttpRequest) -> Format {
+        let ranked = match Accept::parse(req) {
+            Ok(accept) if !accept.is_empty() => accept.ranked(),
+            _ => return Format::ProblemJson,
+        };
+        ranked
+            .iter()
+            .find_map(|mime| match (mime.type_().as_str(), mime.subtype().as_str()) {
+                ("application", "problem+json") | ("application", "*") => Some(Format::ProblemJson),
+                ("application", "json") => Some(Format::Json),
+                ("text", "html") | ("application", "xhtml+xml") => Some(Format::Html),
+                ("text", _) | ("*", _) => Some(Format::Text),
+                _ => None,
+            })
+            .unwrap_or(Format::ProblemJson)
+    }
+}
+
+// RFC 7807 problem details; every error the application answers with renders as
+// one. With the default `about:blank` type the title is the status's reason phrase.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: http::StatusCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // Path of the request, filled in from the `Problems` scope when not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    // Extension members, serialized next to the standard ones.
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+fn serialize_status<S: Serializer>(status: &http::StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
+}
+
+impl Problem {
+    pub fn new(status: http::StatusCode) -> Self {
+        Problem {
+            type_uri: String::from("about:blank"),
+            title: status.canonical_reason().unwrap_or("Error").to_owned(),
+            status,
+            detail: None,
+            instance: None,
+            request_id: None,
+            extensions: Map::new(),
+        }
+    }
+
+    // Status and message of any other error type.
+    pub fn from_error(err: &dyn ResponseError) -> Self {
+        Problem::new(err.status_code()).with_detail(err)
+    }
+
+    pub fn with_type(mut self, type_uri: &str, title: &str) -> Self {
+        self.type_uri = type_uri.to_owned();
+        self.title = title.to_owned();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: impl fmt::Display) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_instance(mut self, instance: &str) -> Self {
+        self.instance = Some(instance.to_owned());
+        self
+    }
+
+    pub fn with_extension(mut self, name: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.extensions.insert(name.to_owned(), value);
+        self
+    }
+
+    // Rendered in the format the request asked for; outside a `Problems` scope
+    // always as problem+json.
+    pub fn response(&self) -> HttpResponse<body::BoxBody> {
+        let format = CONTEXT.try_with(|context| context.format).unwrap_or(Format::ProblemJson);
+        self.render(format)
+    }
+
+    pub fn render(&self, format: Format) -> HttpResponse<body::BoxBody> {
+        let mut problem = self.clone();
+        problem.request_id = problem.request_id.or_else(|| RequestId::current().map(|id| id.to_string()));
+        problem.instance = problem
+            .instance
+            .or_else(|| CONTEXT.try_with(|context| context.instance.to_string()).ok());
+
+        let mut res = HttpResponse::build(self.status);
+        let mut res = match format {
+            Format::ProblemJson => res
+                .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+                .body(serde_json::to_string(&problem).unwrap_or_default()),
+            Format::Json => res.json(&problem),
+            Format::Html => res.content_type(mime::TEXT_HTML_UTF_8).body(problem.html()),
+            Format::Text => res.content_type(mime::TEXT_PLAIN_UTF_8).body(problem.text()),
+        };
+        res.extensions_mut().insert(Rendered);
+        res
+    }
+
+    fn html(&self) -> String {
+        let optional = |value: &Option<String>| escape_html(value.as_deref().unwrap_or(""));
+        // One pass over the template, so placeholders inside the inserted values
+        // are left as they are.
+        let mut parts = HTML_TEMPLATE.split("{{");
+        let mut html = String::from(parts.next().unwrap_or_default());
+        for part in parts {
+            let Some((name, rest)) = part.split_once("}}") else {
+                html.push_str("{{");
+                html.push_str(part);
+                continue;
+            };
+            match name {
+                "status" => html.push_str(self.status.as_str()),
+                "title" => html.push_str(&escape_html(&self.title)),
+                "detail" => html.push_str(&optional(&self.detail)),
+                "instance" => html.push_str(&optional(&self.instance)),
+                "request_id" => html.push_str(&optional(&self.request_id)),
+                _ => {
+                    html.push_str("{{");
+                    html.push_str(name);
+                    html.push_str("}}");
+                }
+            }
+            html.push_str(rest);
+        }
+        html
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!("{} {}\n", self.status.as_str(), self.title);
+        if let Some(detail) = &self.detail {
+            text.push_str(&format!("{}\n", detail));
+        }
+        if let Some(id) = &self.request_id {
+            text.push_str(&format!("request id: {}\n", id));
+        }
+        text
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{}: {}", self.title, detail),
+            None => f.write_str(&self.title),
+        }
+    }
+}
+
+impl std::error::Error for Problem {}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl ResponseError for Problem {
+    fn status_code(&self) -> http::StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        self.response()
+    }
+}
+
+fn extractor_error(err: impl ResponseError, req: &HttpRequest, type_uri: &str, title: &str) -> Error {
+    Problem::from_error(&err)
+        .with_type(type_uri, title)
+        .with_instance(req.path())
+        .into()
+}
+
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|err, req| extractor_error(err, req, "/problems/invalid-json", "Invalid JSON body"))
+}
+
+pub fn form_config() -> web::FormConfig {
+    web::FormConfig::default()
+        .error_handler(|err, req| extractor_error(err, req, "/problems/invalid-form", "Invalid form body"))
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err, req| extractor_error(err, req, "/problems/invalid-query", "Invalid query string"))
+}
+
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default()
+        .error_handler(|err, req| extractor_error(err, req, "/problems/invalid-path", "Invalid path parameter"))
+}
+
+// Routes the failures of every built-in extractor through `Problem`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(json_config())
+        .app_data(form_config())
+        .app_data(query_config())
+        .app_data(path_config());
+}
+
+// Makes the request path and negotiated format available to problems rendered
+// while serving the request, including by `ResponseError` impls that don't get the
+// request. Error responses that come back without a body, like the 404 of the
+// default service, get one written for their status, and errors whose own body
+// isn't a problem, like `BlockingError` or `UrlGenerationError`, are rewritten as
+// one; server errors don't repeat their message to the client.
+//
+// Wrap it inside `RequestIds` so those bodies can carry the request ID.
+#[derive(Clone, Default)]
+pub struct Problems;
+
+impl<S, B> Transform<S, ServiceRequest> for Problems
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ProblemsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ProblemsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ProblemsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ProblemsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let context = Context {
+            instance: req.path().into(),
+            format: Format::negotiate(req.request()),
+        };
+        let service = Rc::clone(&self.service);
+        Box::pin(CONTEXT.scope(context, async move {
+            let res = match service.call(req).await {
+                Ok(res) => res,
+                // Render errors of inner middleware here, inside the scope.
+                Err(err) => {
+                    let res = err.error_response();
+                    let res = match replacement(&res) {
+                        Some(problem) => rewrite(problem, &res),
+                        None => res,
+                    };
+                    return Err(InternalError::from_response(err, res).into());
+                }
+            };
+            let Some(problem) = replacement(res.response()) else {
+                return Ok(res.map_into_left_body());
+            };
+
+            let (req, original) = res.into_parts();
+            Ok(ServiceResponse::new(req, rewrite(problem, &original)).map_into_right_body())
+        }))
+    }
+}
+
+// The problem to answer with instead of `res`, if it is an error response that
+// `Problem` didn't write.
+fn replacement<B: MessageBody>(res: &HttpResponse<B>) -> Option<Problem> {
+    let status = res.status();
+    if !(status.is_client_error() || status.is_server_error()) || res.extensions().contains::<Rendered>() {
+        return None;
+    }
+    match res.error() {
+        Some(err) if status.is_client_error() => Some(Problem::new(status).with_detail(err)),
+        Some(_) => Some(Problem::new(status)),
+        None if res.body().size().is_eof() => Some(Problem::new(status)),
+        None => None,
+    }
+}
+
+fn rewrite<B>(problem: Problem, original: &HttpResponse<B>) -> HttpResponse<body::BoxBody> {
+    let mut res = problem.response();
+    // Keep headers such as `Allow` or `WWW-Authenticate` of the original response.
+    for (name, value) in original.headers() {
+        if !res.headers().contains_key(name) && name != header::CONTENT_LENGTH {
+            res.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::{RequestIds, X_REQUEST_ID};
+    use crate::routes;
+    use crate::stats::Stats;
+    use actix_web::{error, test, App};
+
+    #[actix_web::test]
+    async fn test_errors_render_as_problem_json() {
+        let app = test::init_service(
+            App::new()
+                .app_data(routes::extractors::state(&Stats::default()))
+                .wrap(Problems)
+                .wrap(RequestIds)
+                .configure(configure)
+                .configure(routes::error_routes)
+                .configure(routes::extractor_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/custom-error-enum")
+            .insert_header((X_REQUEST_ID, "abc-123"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), CONTENT_TYPE);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Internal Server Error",
+                "status": 500,
+                "detail": "internal error",
+                "instance": "/custom-error-enum",
+                "request_id": "abc-123",
+            })
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/json")
+            .insert_header((http::header::CONTENT_TYPE, "application/json"))
+            .set_payload("{not json")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["type"], "/problems/invalid-json");
+        assert_eq!(body["instance"], "/json");
+
+        let req = test::TestRequest::get().uri("/query").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["type"], "/problems/invalid-query");
+        assert_eq!(body["status"], 400);
+
+        let req = test::TestRequest::post().uri("/form").set_payload("username=a").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["type"], "/problems/invalid-form");
+    }
+
+    #[actix_web::test]
+    async fn test_error_bodies_follow_accept() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Problems)
+                .wrap(RequestIds)
+                .configure(routes::error_routes)
+                .default_service(web::route().method(http::Method::GET)),
+        )
+        .await;
+
+        let get = |accept: &str| {
+            test::TestRequest::get()
+                .uri("/missing")
+                .insert_header((http::header::ACCEPT, accept.to_owned()))
+                .insert_header((X_REQUEST_ID, "abc-123"))
+                .to_request()
+        };
+
+        let res = test::call_service(&app, get("text/html,application/xhtml+xml,*/*;q=0.8")).await;
+        assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(body.contains("<h1>404</h1>") && body.contains("abc-123"), "{}", body);
+
+        let res = test::call_service(&app, get("*/*")).await;
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(test::read_body(res).await, "404 Not Found\nrequest id: abc-123\n");
+
+        let res = test::call_service(&app, get("application/json")).await;
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/json");
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], 404);
+
+        let req = test::TestRequest::get()
+            .uri("/custom-error")
+            .insert_header((http::header::ACCEPT, "text/html"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("<p>my error: test</p>"));
+    }
+
+    #[actix_web::test]
+    async fn test_plain_actix_errors_become_problems() {
+        async fn unnamed(req: HttpRequest) -> actix_web::Result<HttpResponse> {
+            let url = req.url_for("no-such-route", ["1"])?;
+            Ok(HttpResponse::Ok().body(url.to_string()))
+        }
+
+        let app = test::init_service(
+            App::new()
+                .wrap(Problems)
+                .route("/bad", web::get().to(|| async { Err::<HttpResponse, _>(error::ErrorBadRequest("plain text")) }))
+                .route("/url", web::get().to(unnamed)),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/bad").to_request()).await;
+        assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), CONTENT_TYPE);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["detail"], "plain text");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/url").to_request()).await;
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), CONTENT_TYPE);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["detail"], Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn test_html_escapes_problem_members() {
+        let html = Problem::new(http::StatusCode::BAD_REQUEST).with_detail("<b>\"x\" & 'y'</b>").html();
+        assert!(html.contains("<p>&lt;b&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/b&gt;</p>"));
+
+        let mut problem = Problem::new(http::StatusCode::BAD_REQUEST).with_detail("{{request_id}} {{title}}");
+        problem.request_id = Some(String::from("abc-123"));
+        let html = problem.html();
+        assert!(html.contains("<p>{{request_id}} {{title}}</p>"), "{}", html);
+        assert_eq!(html.matches("abc-123").count(), 1, "{}", html);
+    }
+}
@@ -0,0 +1,379 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{io, net};
+
+use crate::shutdown::Shutdown;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Longest v1 line allowed by the spec, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+// v2 headers may carry TLVs; anything bigger than this is not from a sane balancer.
+const V2_MAX_LEN: usize = 16 + 1024;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq, derive_more::Display)]
+pub enum HeaderError {
+    #[display(fmt = "connection does not start with a PROXY protocol header")]
+    Missing,
+    #[display(fmt = "malformed PROXY protocol header: {}", _0)]
+    Malformed(&'static str),
+}
+
+// A complete header: the client address it announces, if any (`LOCAL` and `UNKNOWN`
+// connections, such as the balancer's own health checks, have none), and its length.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    pub source: Option<SocketAddr>,
+    pub len: usize,
+}
+
+// Parses a v1 or v2 header at the start of `buf`. `Ok(None)` means more bytes are needed.
+pub fn parse(buf: &[u8]) -> Result<Option<Header>, HeaderError> {
+    if buf.len() < V2_SIGNATURE.len() && V2_SIGNATURE.starts_with(buf) {
+        return Ok(None);
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+    if buf.len() < V1_PREFIX.len() && V1_PREFIX.starts_with(buf) {
+        return Ok(None);
+    }
+    if buf.starts_with(V1_PREFIX) {
+        return parse_v1(buf);
+    }
+    Err(HeaderError::Missing)
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, HeaderError> {
+    let end = match buf.windows(2).position(|pair| pair == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Err(HeaderError::Malformed("v1 line too long")),
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(HeaderError::Malformed("v1 line too long")),
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| HeaderError::Malformed("v1 line is not ASCII"))?;
+    let len = end + 2;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(Some(Header { source: None, len })),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| HeaderError::Malformed("bad v1 source address"))?;
+            let port: u16 = port.parse().map_err(|_| HeaderError::Malformed("bad v1 source port"))?;
+            Ok(Some(Header {
+                source: Some(SocketAddr::new(ip, port)),
+                len,
+            }))
+        }
+        _ => Err(HeaderError::Malformed("unsupported v1 line")),
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, HeaderError> {
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let (version, command, family) = (buf[12] >> 4, buf[12] & 0x0f, buf[13] >> 4);
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version != 2 {
+        return Err(HeaderError::Malformed("unsupported v2 version"));
+    }
+    if len > V2_MAX_LEN {
+        return Err(HeaderError::Malformed("v2 header too long"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let addresses = &buf[16..len];
+    let source = match (command, family) {
+        // LOCAL: the balancer speaking for itself
+        (0, _) => None,
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[0..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[0..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (1, 1 | 2) => return Err(HeaderError::Malformed("v2 address block too short")),
+        // AF_UNSPEC and AF_UNIX carry no address we can use
+        (1, _) => None,
+        _ => return Err(HeaderError::Malformed("unsupported v2 command")),
+    };
+    Ok(Some(Header { source, len }))
+}
+
+// Client addresses of relayed connections, keyed by the relay's end of the loopback
+// connection, which is the peer address the HttpServer sees, and the loopback
+// listeners those connections arrive on.
+#[derive(Clone, Default)]
+pub struct ProxiedPeers {
+    peers: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+    relays: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl ProxiedPeers {
+    pub fn source(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        self.lock().get(&peer).copied()
+    }
+
+    // Whether `local` is the address of a relay's loopback listener. Any local process
+    // can connect to it; only connections the relay registered carry a PROXY header.
+    pub fn is_relay(&self, local: SocketAddr) -> bool {
+        locked(&self.relays).contains(&local)
+    }
+
+    fn register(&self, peer: SocketAddr, source: SocketAddr) -> Registration {
+        self.lock().insert(peer, source);
+        Registration {
+            peers: self.clone(),
+            peer,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, SocketAddr>> {
+        locked(&self.peers)
+    }
+}
+
+fn locked<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Registration {
+    peers: ProxiedPeers,
+    peer: SocketAddr,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.peers.lock().remove(&self.peer);
+    }
+}
+
+// actix-web's HttpServer starts reading (or the TLS handshake) as soon as it accepts a
+// connection, so the PROXY header can't be consumed in front of it. A PROXY listener is
+// therefore served by a relay task: it reads the header, forwards the rest of the stream
+// to a loopback listener that the HttpServer accepts on, and records which loopback
+// connection carries which client. Returns that loopback listener.
+//
+// The relay runs on the System's thread, not the workers: every proxied byte is
+// copied once more over loopback, and all PROXY listeners together are limited to
+// what that one thread can copy, whatever `server.workers` says. Reading the header
+// in the worker would need an `HttpService` built outside `HttpServer`, which can't
+// set the `AppConfig` (scheme, host) that `HttpServer` gives its apps.
+pub fn relay(public: net::TcpListener, peers: ProxiedPeers, shutdown: Shutdown) -> io::Result<net::TcpListener> {
+    let internal = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let upstream = internal.local_addr()?;
+    locked(&peers.relays).insert(upstream);
+    public.set_nonblocking(true)?;
+    let public = tokio::net::TcpListener::from_std(public)?;
+
+    actix_web::rt::spawn(async move {
+        loop {
+            let (client, peer) = match public.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn!("PROXY protocol listener failed to accept: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            // The HttpServer's own listeners are paused while draining; do the same here.
+            if shutdown.is_draining() {
+                continue;
+            }
+            actix_web::rt::spawn(relay_connection(client, peer, upstream, peers.clone()));
+        }
+    });
+
+    Ok(internal)
+}
+
+async fn relay_connection(mut client: TcpStream, peer: SocketAddr, upstream: SocketAddr, peers: ProxiedPeers) {
+    let (header, early_data) = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut client)).await {
+        Ok(Ok(header)) => header,
+        Ok(Err(err)) => return log::debug!("dropping connection from {}: {}", peer, err),
+        Err(_) => return log::debug!("dropping connection from {}: no PROXY protocol header", peer),
+    };
+
+    // Register the loopback address before connecting, so the HttpServer never sees
+    // the connection while it is still unknown.
+    let socket = match TcpSocket::new_v4().and_then(|socket| {
+        socket.bind((Ipv4Addr::LOCALHOST, 0).into())?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(err) => return log::warn!("cannot relay connection from {}: {}", peer, err),
+    };
+    let _registration = match socket.local_addr() {
+        Ok(local) => peers.register(local, header.source.unwrap_or(peer)),
+        Err(err) => return log::warn!("cannot relay connection from {}: {}", peer, err),
+    };
+    let mut server = match socket.connect(upstream).await {
+        Ok(server) => server,
+        Err(err) => return log::warn!("cannot relay connection from {}: {}", peer, err),
+    };
+
+    if server.write_all(&early_data).await.is_ok() {
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+    }
+}
+
+// Reads until a complete header has arrived and returns it with any bytes that came after it.
+async fn read_header(stream: &mut TcpStream) -> io::Result<(Header, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match parse(&buf) {
+            Ok(Some(header)) => {
+                let rest = buf.split_off(header.len);
+                return Ok((header, rest));
+            }
+            Ok(None) => continue,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forwarded::Forwarded;
+    use crate::routes;
+    use crate::settings::Settings;
+    use actix_web::{App, HttpServer};
+
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_parse_v1() {
+        let line = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 443\r\nGET / HTTP/1.1\r\n";
+        let header = parse(line).unwrap().unwrap();
+        assert_eq!(header.source, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(&line[header.len..], b"GET / HTTP/1.1\r\n");
+
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n";
+        assert_eq!(parse(line).unwrap().unwrap().source, Some("[2001:db8::1]:51000".parse().unwrap()));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap().source, None);
+
+        assert_eq!(parse(b"PRO").unwrap(), None);
+        assert_eq!(parse(b"PROXY TCP4 203.0.113.7").unwrap(), None);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").unwrap_err(), HeaderError::Missing);
+        assert!(parse(b"PROXY TCP4 nonsense 10.0.0.1 1 2\r\n").is_err());
+        assert!(parse(&[b'P'; 200]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51000u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+
+        assert_eq!(parse(&header[..20]).unwrap(), None);
+        let mut stream = header.clone();
+        stream.extend(b"\x16\x03\x01");
+        let parsed = parse(&stream).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(parsed.len, header.len());
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(parse(&local).unwrap().unwrap(), Header { source: None, len: 16 });
+
+        header[12] = 0x31;
+        assert!(parse(&header).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_relay_reports_client_address() {
+        let peers = ProxiedPeers::default();
+        let public = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = public.local_addr().unwrap().port();
+        let internal = relay(public, peers.clone(), Shutdown::new(&Settings::default())).unwrap();
+
+        let forwarded = Forwarded::new(&Settings::default(), peers);
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(forwarded.clone())
+                .configure(routes::extractor_routes)
+        })
+        .workers(1)
+        .listen(internal)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let response = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(
+                stream,
+                "PROXY TCP4 203.0.113.7 127.0.0.1 51000 {}\r\nGET /client-info HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                port
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let client: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(client["ip"], "203.0.113.7");
+        assert_eq!(client["scheme"], "http");
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_relay_listener_rejects_direct_connections() {
+        let peers = ProxiedPeers::default();
+        let public = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let internal = relay(public, peers.clone(), Shutdown::new(&Settings::default())).unwrap();
+        let upstream = internal.local_addr().unwrap();
+
+        let forwarded = Forwarded::new(&Settings::default(), peers);
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(forwarded.clone())
+                .configure(routes::extractor_routes)
+        })
+        .workers(1)
+        .listen(internal)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let response = actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = net::TcpStream::connect(upstream).unwrap();
+            write!(stream, "GET /client-info HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+        handle.stop(false).await;
+    }
+}
@@ -0,0 +1,219 @@
+use actix_web::dev::{self, forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::error::{BlockingError, InternalError};
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::problem::Problem;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Incoming IDs longer than this, or with characters outside `[A-Za-z0-9._:-]`, are
+// replaced so they can't inject anything into log lines or headers.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+// Correlation ID of the request being served, taken from `X-Request-Id` or generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(Rc<str>);
+
+impl RequestId {
+    fn generate() -> Self {
+        RequestId(uuid::Uuid::new_v4().to_string().into())
+    }
+
+    fn parse(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"._:-".contains(&b));
+        valid.then(|| RequestId(value.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // ID of the request whose future is currently being polled. Used by the log
+    // formatter and by `ResponseError` impls, which don't get the request.
+    pub fn current() -> Option<RequestId> {
+        CURRENT.try_with(RequestId::clone).ok()
+    }
+}
+
+// `web::block` for handlers: task-locals don't follow the closure onto the blocking
+// thread, so the request's ID is set again there for logs and errors written by `f`.
+pub async fn block<F, R>(f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let id = RequestId::current().map(|id| String::from(id.as_str()));
+    web::block(move || match id {
+        Some(id) => CURRENT.sync_scope(RequestId(id.into()), f),
+        None => f(),
+    })
+    .await
+}
+
+// `Logger` writes its line after the response body is sent, outside the request's
+// scope, so the access log reads the ID back from the response header instead.
+pub const ACCESS_LOG_FORMAT: &str = r#"[%{x-request-id}o] %a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+// env_logger format that tags records written while serving a request with its ID.
+// JSON access lines already carry the ID and are written as they are, one object per line.
+pub fn format_log(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    if record.target() == crate::access_log::TARGET {
+        return writeln!(buf, "{}", record.args());
+    }
+    let timestamp = buf.timestamp();
+    let level = buf.default_styled_level(record.level());
+    match RequestId::current() {
+        Some(id) => writeln!(buf, "[{} {:<5} {}] [{}] {}", timestamp, level, record.target(), id, record.args()),
+        None => writeln!(buf, "[{} {:<5} {}] {}", timestamp, level, record.target(), record.args()),
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let id = req.extensions().get::<RequestId>().cloned();
+        ready(id.ok_or_else(|| {
+            Problem::new(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_detail("request id middleware is not installed")
+                .into()
+        }))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RequestIds;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIds
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(RequestId::parse)
+            .unwrap_or_else(RequestId::generate);
+        req.extensions_mut().insert(id.clone());
+
+        let header = HeaderValue::from_str(id.as_str()).unwrap();
+        let service = Rc::clone(&self.service);
+        Box::pin(CURRENT.scope(id, async move {
+            match service.call(req).await {
+                Ok(mut res) => {
+                    res.headers_mut().insert(X_REQUEST_ID, header);
+                    Ok(res)
+                }
+                // Render the error here, inside the scope, so `error_response` can see the ID.
+                Err(err) => {
+                    let mut res = err.error_response();
+                    res.headers_mut().insert(X_REQUEST_ID, header);
+                    Err(InternalError::from_response(err, res).into())
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use actix_web::{test, web, App};
+
+    async fn echo(id: RequestId) -> String {
+        assert_eq!(RequestId::current(), Some(id.clone()));
+        id.to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_generated_or_accepted() {
+        let app = test::init_service(App::new().wrap(RequestIds).route("/", web::get().to(echo))).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let generated = res.headers().get(&X_REQUEST_ID).unwrap().to_str().unwrap().to_owned();
+        assert!(uuid::Uuid::parse_str(&generated).is_ok());
+        assert_eq!(test::read_body(res).await, generated.as_bytes());
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "upstream-42"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(&X_REQUEST_ID).unwrap(), "upstream-42");
+        assert_eq!(test::read_body(res).await, "upstream-42");
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "bad id\t"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_ne!(res.headers().get(&X_REQUEST_ID).unwrap(), "bad id\t");
+    }
+
+    #[actix_web::test]
+    async fn test_error_bodies_carry_request_id() {
+        let app = test::init_service(App::new().wrap(RequestIds).configure(routes::error_routes)).await;
+
+        for uri in ["/custom-error", "/custom-error-enum"] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header((X_REQUEST_ID, "abc-123"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.headers().get(&X_REQUEST_ID).unwrap(), "abc-123");
+            let body = test::read_body(res).await;
+            assert!(std::str::from_utf8(&body).unwrap().contains("abc-123"), "{:?}", body);
+        }
+    }
+}
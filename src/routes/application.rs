@@ -1,20 +1,48 @@
-use actix_web::{get, web, guard, Responder, HttpResponse};
+use actix_web::{get, web, Responder, HttpResponse};
 use actix_web::post;
+use serde_json::json;
 
-use std::sync::Mutex;
+use std::sync::RwLock;
+
+use crate::counter::{CounterStore, StoreError};
+use crate::request_id;
+use crate::routes::Route;
 
 pub struct AppStateWithCounter {
     pub app_name: String,
-    pub counter: Mutex<i32>,
+    pub counter: Box<dyn CounterStore>,
+    // Outcome of the last store call, for health checks and metrics, which run on
+    // the workers and must not block on the store themselves.
+    last: RwLock<Result<u64, String>>,
+}
+
+impl AppStateWithCounter {
+    // Call from inside `request_id::block` with the result of a store call.
+    pub fn record(&self, result: Result<u64, StoreError>) -> Result<u64, StoreError> {
+        let last = result.as_ref().copied().map_err(ToString::to_string);
+        *self.last.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = last;
+        result
+    }
+
+    pub fn last(&self) -> Result<u64, String> {
+        self.last.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
 }
 
 #[get("/")]
-async fn index(data: web::Data<AppStateWithCounter>) -> String {
+async fn index(data: web::Data<AppStateWithCounter>) -> actix_web::Result<String> {
+    let state = data.clone();
+    let counter = request_id::block(move || state.record(state.counter.increment())).await??;
     let app_name = &data.app_name;
-    let mut counter = data.counter.lock().unwrap();
-    *counter += 1;
 
-    format!("Hello {app_name}, Request number: {counter}")
+    Ok(format!("Hello {app_name}, Request number: {counter}"))
+}
+
+// Reads the visit counter without counting the request.
+#[get("/counter")]
+async fn current_count(data: web::Data<AppStateWithCounter>) -> actix_web::Result<HttpResponse> {
+    let count = request_id::block(move || data.record(data.counter.get())).await??;
+    Ok(HttpResponse::Ok().json(json!({ "count": count })))
 }
 
 #[get("/hello")]
@@ -22,11 +50,6 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
-#[get("/show")]
-async fn show_users() -> impl Responder {
-    HttpResponse::Ok().body("Alice, Bob, Chris, Dan, Eve")
-}
-
 #[post("/echo")]
 async fn echo(req_body: String) -> impl Responder {
     HttpResponse::Ok().body(req_body)
@@ -41,30 +64,36 @@ async fn app() -> impl Responder {
 }
 
 
-pub fn init_routes(config: &mut web::ServiceConfig) {
-    let counter = web::Data::new(AppStateWithCounter {
+// Created once in `main` so every worker and the health checks share the same counter.
+// Reads the store once up front, before any worker is running.
+pub fn counter(store: Box<dyn CounterStore>) -> web::Data<AppStateWithCounter> {
+    let last = store.get().map_err(|err| err.to_string());
+    web::Data::new(AppStateWithCounter {
         app_name: String::from("Actix Web"),
-        counter: Mutex::new(0),
-    });
+        counter: store,
+        last: RwLock::new(last),
+    })
+}
+
+pub const ROUTES: &[Route] = &[
+    ("/", &["GET"]),
+    ("/counter", &["GET"]),
+    ("/hello", &["GET"]),
+    ("/echo", &["POST"]),
+    ("/app/index.html", &["GET"]),
+    ("/hey", &["GET"]),
+    ("/app1", &["GET"]),
+    ("/test", &["GET"]),
+];
 
-    let users_scope = web::scope("/users").service(show_users);
+pub fn init_routes(config: &mut web::ServiceConfig) {
     let app_scope = web::scope("/app")
         .route("/index.html", web::get().to(app));
 
-    let www_guard = web::scope("/")
-        .guard(guard::Header("Host", "www.rust-lang.org"))
-        .route("", web::to(|| async { HttpResponse::Ok().body("www") }));
-    let user_guard = web::scope("/")
-        .guard(guard::Header("Host", "users.rust-lang.org"))
-        .route("", web::to(|| async { HttpResponse::Ok().body("user") }));
-
-    config.app_data(counter);
-    config.service(www_guard);
-    config.service(user_guard);
     config.service(index);
+    config.service(current_count);
     config.service(hello);
     config.service(echo);
-    config.service(users_scope);
     config.service(app_scope);
     config.route("/hey", web::get().to(manual_hello));
     config.service(
@@ -78,3 +107,22 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
             .route(web::head().to(HttpResponse::MethodNotAllowed)),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http, test, App};
+
+    #[actix_web::test]
+    async fn test_head_is_not_allowed() {
+        let app = test::init_service(App::new().configure(init_routes)).await;
+        for (path, body) in [("/app1", "app1"), ("/test", "test")] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            assert_eq!(test::call_and_read_body(&app, req).await, body);
+
+            let req = test::TestRequest::default().method(http::Method::HEAD).uri(path).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+    }
+}
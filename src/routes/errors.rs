@@ -2,13 +2,20 @@ use actix_web::{get, web, http, body, error, Result, HttpResponse};
 use actix_files::NamedFile;
 use log::info;
 
+use crate::problem::Problem;
+use crate::routes::Route;
+
 #[derive(Debug, derive_more::Display, derive_more::Error)]
 #[display(fmt = "my error: {}", name)]
 struct CustomError {
     name: &'static str,
 }
 
-impl error::ResponseError for CustomError {}
+impl error::ResponseError for CustomError {
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        Problem::from_error(self).response()
+    }
+}
 
 #[derive(Debug, derive_more::Display)]
 enum CustomErrorEnum {
@@ -22,9 +29,7 @@ enum CustomErrorEnum {
 
 impl error::ResponseError for CustomErrorEnum {
     fn error_response(&self) -> HttpResponse<body::BoxBody> {
-        HttpResponse::build(self.status_code())
-            .insert_header(http::header::ContentType::html())
-            .body(self.to_string())
+        Problem::from_error(self).response()
     }
 
     fn status_code(&self) -> http::StatusCode {
@@ -38,7 +43,7 @@ impl error::ResponseError for CustomErrorEnum {
 
 #[get("/static-index")]
 async fn static_index() -> std::io::Result<NamedFile> {
-    Ok(NamedFile::open("static/index.html")?)
+    NamedFile::open("static/index.html")
 }
 
 #[get("/custom-error")]
@@ -49,8 +54,8 @@ async fn custom_error() -> Result<&'static str, CustomError> {
 #[get("/custom-error-enum")]
 async fn custom_error_enum() -> Result<&'static str, CustomErrorEnum> {
     let internal_error = Err(CustomErrorEnum::InternalError)?;
-    let _bad_client_data = Err(CustomErrorEnum::BadClientData)?;
-    let _timeout = Err(CustomErrorEnum::Timeout)?;
+    Err(CustomErrorEnum::BadClientData)?;
+    Err(CustomErrorEnum::Timeout)?;
 
     internal_error
 }
@@ -58,7 +63,7 @@ async fn custom_error_enum() -> Result<&'static str, CustomErrorEnum> {
 #[get("/map-err")]
 async fn map_err() -> Result<&'static str> {
     let result: Result<&'static str, CustomError> = Err(CustomError { name: "test error" });
-    Ok(result.map_err(|e| error::ErrorBadRequest(e.name))?)
+    Ok(result.map_err(|e| Problem::new(http::StatusCode::BAD_REQUEST).with_detail(e.name))?)
 }
 
 #[get("/err-logging")]
@@ -68,6 +73,14 @@ async fn err_logging() -> Result<&'static str, CustomError> {
     Err(err)
 }
 
+pub const ROUTES: &[Route] = &[
+    ("/static-index", &["GET"]),
+    ("/custom-error", &["GET"]),
+    ("/custom-error-enum", &["GET"]),
+    ("/map-err", &["GET"]),
+    ("/err-logging", &["GET"]),
+];
+
 pub fn init_routes(config: &mut web::ServiceConfig) {
   config.service(static_index);
   config.service(custom_error);
@@ -1,107 +1,308 @@
-use actix_web::{get, post, web, error, Result, Responder, HttpRequest, HttpResponse};
-use serde::Deserialize;
+use actix_web::{get, post, web, http, guard, dev, Error, FromRequest, HttpMessage, Result, Responder, HttpRequest, HttpResponse};
+use actix_web::error::InternalError;
+use futures::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
 
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::cell::Cell;
+use std::net::IpAddr;
 
-#[derive(Deserialize)]
+use crate::path_params::PathParams;
+use crate::problem::{self, Problem};
+use crate::routes::Route;
+use crate::shutdown::Shutdown;
+use crate::stats::{Stats, WorkerStats};
+use crate::validation::{not_blank, Validated, USERNAME};
+
+#[derive(Deserialize, Validate)]
 pub struct Extractors {
+    #[validate(range(min = 1))]
     pub id: u32,
+    #[validate(length(min = 1, max = 32), regex = "USERNAME")]
     pub username: String,
 }
 
-#[derive(Deserialize)]
+#[allow(dead_code)]
+#[derive(Deserialize, Validate)]
 pub struct PostInfo {
+    #[validate(range(min = 1))]
     pub post_id: u32,
+    #[validate(length(min = 1, max = 32), regex = "USERNAME")]
     pub friend: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct QueryStruct {
+    #[validate(length(min = 1, max = 64))]
     name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct JsonStruct {
+    #[validate(length(max = 64), custom = "not_blank")]
     name: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 struct FormData {
+    #[validate(length(min = 1, max = 32), regex = "USERNAME")]
     username: String,
 }
 
-#[derive(Clone)]
-pub struct StateStruct {
-    pub local_count: Cell<usize>,
-    pub global_count: Arc<AtomicUsize>,
+// Statistics counter behind /add-one and /count.
+pub const ADD_ONE: &str = "add_one";
+
+#[derive(Deserialize)]
+struct ResetQuery {
+    counter: Option<String>,
+}
+
+// Verified client certificate of a mutual TLS connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerIdentity {
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    pub fingerprint: String,
+}
+
+impl FromRequest for PeerIdentity {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let identity = req
+            .conn_data::<PeerIdentity>()
+            .cloned()
+            .or_else(|| req.extensions().get::<PeerIdentity>().cloned());
+
+        ready(identity.ok_or_else(|| Problem::new(http::StatusCode::UNAUTHORIZED).with_detail("client certificate required").into()))
+    }
+}
+
+// Credentials of the process on the other end of a Unix domain socket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl FromRequest for PeerCredentials {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let credentials = req.conn_data::<PeerCredentials>().copied();
+        ready(credentials.ok_or_else(|| {
+            Problem::new(http::StatusCode::FORBIDDEN)
+                .with_detail("peer credentials are only available on Unix socket connections")
+                .into()
+        }))
+    }
+}
+
+// Requests carrying `admin.token` as `Authorization: Bearer <token>`. Without a
+// configured token the admin endpoints don't exist and answer 404.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        ready(authorize_admin(req))
+    }
+}
+
+fn authorize_admin(req: &HttpRequest) -> Result<Admin> {
+    let shutdown = req
+        .app_data::<web::Data<Shutdown>>()
+        .filter(|shutdown| shutdown.is_enabled())
+        .ok_or_else(|| Problem::new(http::StatusCode::NOT_FOUND).with_detail("admin endpoints are disabled"))?;
+    let token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if token.is_some_and(|token| shutdown.authorize(token)) {
+        return Ok(Admin);
+    }
+
+    let problem = Problem::new(http::StatusCode::UNAUTHORIZED).with_detail("a valid admin bearer token is required");
+    let mut res = problem.response();
+    res.headers_mut()
+        .insert(http::header::WWW_AUTHENTICATE, http::header::HeaderValue::from_static("Bearer"));
+    Err(InternalError::from_response(problem, res).into())
+}
+
+// Address and scheme of the client as resolved by the `Forwarded` middleware from
+// PROXY protocol headers and trusted proxies. Without the middleware it falls back
+// to the socket's peer address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub scheme: &'static str,
+}
+
+impl ClientInfo {
+    pub fn of(req: &HttpRequest) -> Self {
+        req.extensions().get::<ClientInfo>().copied().unwrap_or_else(|| ClientInfo {
+            ip: req.peer_addr().map(|addr| addr.ip()),
+            scheme: if req.app_config().secure() { "https" } else { "http" },
+        })
+    }
+}
+
+impl FromRequest for ClientInfo {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        ready(Ok(ClientInfo::of(req)))
+    }
+}
+
+// The virtual host serving the request, picked by `vhost::VirtualHosts` from the
+// host the client asked for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActiveHost {
+    pub name: String,
+    // Lowercased and without the port.
+    pub host: String,
+}
+
+impl FromRequest for ActiveHost {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let host = req.extensions().get::<ActiveHost>().cloned();
+        ready(host.ok_or_else(|| Problem::new(http::StatusCode::NOT_FOUND).with_detail("no virtual host serves this host name").into()))
+    }
+}
+
+// Guards can only see request data, so the connection's identity is copied there first.
+pub fn attach_peer_identity(req: &dev::ServiceRequest) {
+    if let Some(identity) = req.conn_data::<PeerIdentity>().cloned() {
+        req.extensions_mut().insert(identity);
+    }
+}
+
+pub struct ClientCertGuard;
+
+impl guard::Guard for ClientCertGuard {
+    fn check(&self, ctx: &guard::GuardContext<'_>) -> bool {
+        ctx.req_data().contains::<PeerIdentity>()
+    }
 }
 
 #[get("/extractors")]
-async fn extractors(path: web::Path<(String, String)>, info: web::Json<Extractors>) -> impl Responder {
+async fn extractors(path: web::Path<(String, String)>, info: Validated<web::Json<Extractors>>) -> impl Responder {
     let path = path.into_inner();
     format!("{} {} {} {}", path.0, path.1, info.id, info.username)
 }
 
 #[get("/posts/{post_id}/{friend}")]
 async fn post_friend(req: HttpRequest) -> Result<String> {
-    let name: String = req.match_info().get("friend").unwrap().parse().unwrap();
-    let postid: i32 = req.match_info().query("post_id").parse().unwrap();
+    let name: String = req.param("friend")?;
+    let postid: i32 = req.param("post_id")?;
 
     Ok(format!("Welcome {}, post_id: {}", name, postid))
 }
 
 #[get("/query")]
-async fn query(info: web::Query<QueryStruct>) -> String {
+async fn query(info: Validated<web::Query<QueryStruct>>) -> String {
     format!("Welcome {}", info.name)
 }
 
 #[post("/json")]
-async fn json(info: web::Json<JsonStruct>) -> Result<String> {
+async fn json(info: Validated<web::Json<JsonStruct>>) -> Result<String> {
     Ok(format!("Welcome {}", info.name))
 }
 
 #[post("/form")]
-async fn form(form: web::Form<FormData>) -> Result<String> {
+async fn form(form: Validated<web::Form<FormData>>) -> Result<String> {
     Ok(format!("Welcome {}", form.username))
 }
 
+// Only counts requests served by this worker; /stats shows every worker.
 #[get("/count")]
-async fn show_count(data: web::Data<StateStruct>) -> impl Responder {
-    format!("count: {}", data.local_count.get())
+async fn show_count(worker: web::Data<WorkerStats>) -> impl Responder {
+    format!("count: {} (worker {})", worker.get(ADD_ONE), worker.id())
 }
 
 #[get("/add-one")]
-async fn add_one(data: web::Data<StateStruct>) -> impl Responder {
-    data.global_count.fetch_add(1, Ordering::Relaxed);
+async fn add_one(worker: web::Data<WorkerStats>) -> impl Responder {
+    format!("Count: {}", worker.increment(ADD_ONE))
+}
+
+#[get("/stats")]
+async fn show_stats(stats: web::Data<Stats>) -> HttpResponse {
+    HttpResponse::Ok().json(stats.snapshot())
+}
+
+// Resets `?counter=NAME`, or every counter when it is left out.
+#[post("/stats/reset")]
+async fn reset_stats(_admin: Admin, stats: web::Data<Stats>, reset: web::Query<ResetQuery>) -> HttpResponse {
+    stats.reset(reset.counter.as_deref());
+    HttpResponse::Ok().json(stats.snapshot())
+}
+
+#[get("/client-cert")]
+async fn client_cert(identity: Option<PeerIdentity>) -> HttpResponse {
+    match identity {
+        Some(identity) => HttpResponse::Ok().json(identity),
+        None => HttpResponse::Ok().body("anonymous"),
+    }
+}
+
+async fn client_cert_required(identity: PeerIdentity) -> String {
+    format!("Welcome {}", identity.common_name.unwrap_or_default())
+}
 
-    let count = data.local_count.get();
-    data.local_count.set(count + 1);
+#[get("/client-info")]
+async fn client_info(client: ClientInfo) -> HttpResponse {
+    HttpResponse::Ok().json(client)
+}
 
-    format!("Count: {}", data.local_count.get())
+#[get("/active-host")]
+async fn active_host(host: ActiveHost) -> HttpResponse {
+    HttpResponse::Ok().json(host)
+}
+
+#[get("/peer-credentials")]
+async fn peer_credentials(credentials: PeerCredentials) -> HttpResponse {
+    HttpResponse::Ok().json(credentials)
 }
 
 fn json_config() -> web::JsonConfig {
-    web::JsonConfig::default()
-        .limit(4096)
-        .error_handler(|err, _req| {
-            error::InternalError::from_response(
-                err,
-                HttpResponse::Conflict().finish()
-            )
-            .into()
-        })
+    problem::json_config().limit(4096)
 }
 
-pub fn init_routes(config: &mut web::ServiceConfig) {
-    let state_counter = web::Data::new(StateStruct {
-        local_count: Cell::new(0),
-        global_count: Arc::new(AtomicUsize::new(0)),
-    });
+// Runs inside the app factory, so every worker gets its own counters; `stats` is
+// created once in `main` and shared with the metrics exporter.
+pub fn state(stats: &Stats) -> web::Data<WorkerStats> {
+    web::Data::from(stats.worker())
+}
+
+pub const ROUTES: &[Route] = &[
+    ("/extractors", &["GET"]),
+    ("/posts/{post_id}/{friend}", &["GET"]),
+    ("/query", &["GET"]),
+    ("/json", &["POST"]),
+    ("/form", &["POST"]),
+    ("/count", &["GET"]),
+    ("/add-one", &["GET"]),
+    ("/stats", &["GET"]),
+    ("/stats/reset", &["POST"]),
+    ("/client-cert", &["GET"]),
+    ("/client-cert/required", &["GET"]),
+    ("/peer-credentials", &["GET"]),
+    ("/client-info", &["GET"]),
+    ("/active-host", &["GET"]),
+];
 
-    config.app_data(json_config);
-    config.app_data(state_counter);
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.app_data(json_config());
     config.service(extractors);
     config.service(post_friend);
     config.service(query);
@@ -109,4 +310,15 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(form);
     config.service(show_count);
     config.service(add_one);
+    config.service(show_stats);
+    config.service(reset_stats);
+    config.service(client_cert);
+    config.service(
+        web::resource("/client-cert/required")
+            .guard(ClientCertGuard)
+            .route(web::get().to(client_cert_required)),
+    );
+    config.service(peer_credentials);
+    config.service(client_info);
+    config.service(active_host);
 }
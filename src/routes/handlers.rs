@@ -2,6 +2,8 @@ use actix_web::{get, web, http, body, Result, Error, Either, Responder, HttpRequ
 use serde::Serialize;
 use futures::{future::ok, stream::once};
 
+use crate::routes::Route;
+
 #[derive(Serialize)]
 struct CustomType {
     name: &'static str,
@@ -54,6 +56,14 @@ async fn either() -> RegisterResult {
     }
 }
 
+pub const ROUTES: &[Route] = &[
+    ("/responder", &["GET"]),
+    ("/responder2", &["GET"]),
+    ("/custom-type", &["GET"]),
+    ("/stream", &["GET"]),
+    ("/either", &["GET"]),
+];
+
 pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(responder);
     config.service(responder_2);
@@ -0,0 +1,67 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::health::{HealthRegistry, Probe, Status};
+use crate::routes::Route;
+
+fn respond(registry: &HealthRegistry, probe: Probe) -> HttpResponse {
+    let report = registry.report(probe);
+    match report.status {
+        Status::Up | Status::Warn => HttpResponse::Ok().json(report),
+        Status::Down => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz(registry: web::Data<HealthRegistry>) -> HttpResponse {
+    respond(&registry, Probe::Liveness)
+}
+
+#[get("/readyz")]
+async fn readyz(registry: web::Data<HealthRegistry>) -> HttpResponse {
+    respond(&registry, Probe::Readiness)
+}
+
+pub const ROUTES: &[Route] = &[("/healthz", &["GET"]), ("/readyz", &["GET"])];
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(healthz);
+    config.service(readyz);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{self, Check};
+    use crate::settings::Settings;
+    use crate::shutdown::Shutdown;
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn test_readiness_goes_false_while_draining() {
+        let shutdown = Shutdown::new(&Settings::default());
+        let registry = HealthRegistry::default();
+        registry.register("alive", Probe::Liveness, Check::up);
+        registry.register("shutdown", Probe::Readiness, health::not_draining(shutdown.clone()));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(registry))
+                .configure(init_routes),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        shutdown.trigger("test");
+        let res = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], "down");
+        assert_eq!(body["checks"]["shutdown"]["status"], "down");
+        assert_eq!(body["checks"]["alive"]["status"], "up");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,17 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::metrics::Metrics;
+use crate::routes::Route;
+
+#[get("/metrics")]
+async fn metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.render())
+}
+
+pub const ROUTES: &[Route] = &[("/metrics", &["GET"])];
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+    config.service(metrics);
+}
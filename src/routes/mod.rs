@@ -1,15 +1,45 @@
 pub mod application;
 pub mod server;
+pub mod health;
+pub mod metrics;
 pub mod extractors;
 pub mod handlers;
 pub mod errors;
 pub mod url_dispatch;
 pub mod testing;
+pub mod users;
 
 pub use application::init_routes as application_routes;
 pub use server::init_routes as server_routes;
+pub use health::init_routes as health_routes;
+pub use metrics::init_routes as metric_routes;
 pub use extractors::init_routes as extractor_routes;
 pub use handlers::init_routes as handler_routes;
 pub use errors::init_routes as error_routes;
 pub use url_dispatch::init_routes as url_dispatch_routes;
 pub use testing::init_routes as testing_routes;
+pub use users::init_routes as user_routes;
+
+pub type RouteGroup = fn(&mut actix_web::web::ServiceConfig);
+
+// A path pattern and the methods it answers, as registered by a group's
+// `init_routes`. The fallback uses these for 404 suggestions, the `Allow` header
+// of 405s and `OPTIONS`, so keep them in step with the registrations.
+pub type Route = (&'static str, &'static [&'static str]);
+
+// Resources registered without a method guard.
+pub const ANY: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+// Route groups that virtual hosts mount by name, with their route tables.
+pub const GROUPS: &[(&str, RouteGroup, &[Route])] = &[
+    ("application", application_routes, application::ROUTES),
+    ("server", server_routes, server::ROUTES),
+    ("health", health_routes, health::ROUTES),
+    ("metrics", metric_routes, metrics::ROUTES),
+    ("extractors", extractor_routes, extractors::ROUTES),
+    ("handlers", handler_routes, handlers::ROUTES),
+    ("errors", error_routes, errors::ROUTES),
+    ("url_dispatch", url_dispatch_routes, url_dispatch::ROUTES),
+    ("testing", testing_routes, testing::ROUTES),
+    ("users", user_routes, users::ROUTES),
+];
@@ -1,10 +1,40 @@
-use actix_web::{get, http, web, Responder, HttpResponse};
+use actix_web::{get, post, http, web, HttpMessage, Responder, HttpRequest, HttpResponse};
+use base64::Engine;
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tracing::Instrument;
 
+use std::collections::BTreeMap;
 use std::time::Duration;
 
+use crate::problem::Problem;
+use crate::routes::extractors::{Admin, ClientInfo, PeerIdentity};
+use crate::routes::{Route, ANY};
+use crate::settings::{DiagnosticsSettings, KeepAlive, Settings};
+use crate::shutdown::Shutdown;
+use crate::tls::TlsVersion;
+
+#[derive(Serialize)]
+struct Diagnostics<'a> {
+    bind: &'a [String],
+    http_redirect: &'a [String],
+    listen: &'a [String],
+    workers: usize,
+    keep_alive: String,
+    client_request_timeout_secs: u64,
+    client_disconnect_timeout_secs: u64,
+    max_connections_per_worker: usize,
+    shutdown_timeout_secs: u64,
+    tls_backend: String,
+    draining: bool,
+}
+
 #[get("/sleep")]
 async fn sleep() -> impl Responder {
-    tokio::time::sleep(Duration::from_secs(5)).await;
+    tokio::time::sleep(Duration::from_secs(5))
+        .instrument(tracing::info_span!("sleep", seconds = 5))
+        .await;
     "response"
 }
 
@@ -18,7 +48,231 @@ async fn quit() -> HttpResponse {
     res
 }
 
+#[post("/admin/shutdown")]
+async fn admin_shutdown(_admin: Admin, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    if shutdown.is_draining() {
+        return HttpResponse::Accepted().body("already shutting down");
+    }
+    shutdown.trigger("admin endpoint");
+    HttpResponse::Accepted().body("shutting down")
+}
+
+#[get("/diagnostics/server")]
+async fn diagnostics(settings: web::Data<Settings>, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    let server = &settings.server;
+    let keep_alive = match server.keep_alive {
+        KeepAlive::Timeout(secs) => format!("timeout ({}s)", secs),
+        KeepAlive::Os => String::from("os"),
+        KeepAlive::Disabled => String::from("disabled"),
+    };
+
+    HttpResponse::Ok().json(Diagnostics {
+        bind: &server.bind,
+        http_redirect: &server.http_redirect,
+        listen: &server.listen,
+        workers: server.workers,
+        keep_alive,
+        client_request_timeout_secs: server.client_request_timeout,
+        client_disconnect_timeout_secs: server.client_disconnect_timeout,
+        max_connections_per_worker: server.max_connections,
+        shutdown_timeout_secs: server.shutdown_timeout,
+        tls_backend: settings.tls.backend.to_string(),
+        draining: shutdown.is_draining(),
+    })
+}
+
+// Reflects the request back as JSON for debugging clients and proxies.
+async fn echo(req: HttpRequest, mut payload: web::Payload, settings: web::Data<Settings>) -> actix_web::Result<HttpResponse> {
+    let settings = &settings.diagnostics;
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| Problem::from_error(&err))?;
+        if body.len() + chunk.len() > settings.echo_max_body {
+            return Err(Problem::new(http::StatusCode::PAYLOAD_TOO_LARGE)
+                .with_detail(format!("request body exceeds {} bytes", settings.echo_max_body))
+                .into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let mut query = BTreeMap::<String, Vec<String>>::new();
+    for (key, value) in web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default()
+    {
+        query.entry(key).or_default().push(value);
+    }
+    let client = ClientInfo::of(&req);
+    let uri = {
+        let conn = req.connection_info();
+        format!("{}://{}{}", conn.scheme(), conn.host(), req.uri())
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "method": req.method().as_str(),
+        "version": format!("{:?}", req.version()),
+        "uri": uri,
+        "path": req.path(),
+        "query": query,
+        "headers": echo_headers(&req, settings),
+        "peer": {
+            "addr": req.peer_addr().map(|addr| addr.to_string()),
+            "client_ip": client.ip,
+            "scheme": client.scheme,
+        },
+        "tls": req.conn_data::<TlsVersion>().map(|version| json!({
+            "version": version.0,
+            "client_certificate": req.conn_data::<PeerIdentity>(),
+        })),
+        "route": {
+            "pattern": req.match_pattern(),
+            "name": req.match_name(),
+        },
+        "body": echo_body(&req, &body),
+    })))
+}
+
+fn echo_headers(req: &HttpRequest, settings: &DiagnosticsSettings) -> Map<String, Value> {
+    let mut headers = Map::new();
+    for name in req.headers().keys() {
+        let redacted = settings
+            .echo_redact_headers
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(name.as_str()));
+        let values: Vec<Value> = req
+            .headers()
+            .get_all(name)
+            .map(|value| match redacted {
+                true => Value::from("[redacted]"),
+                false => Value::from(String::from_utf8_lossy(value.as_bytes())),
+            })
+            .collect();
+        let value = match <[Value; 1]>::try_from(values) {
+            Ok([value]) => value,
+            Err(values) => Value::Array(values),
+        };
+        headers.insert(name.to_string(), value);
+    }
+    headers
+}
+
+// JSON bodies come back parsed, other text as a string and anything else as base64.
+fn echo_body(req: &HttpRequest, body: &[u8]) -> Value {
+    let mime = req.mime_type().ok().flatten();
+    let is_json = mime
+        .as_ref()
+        .is_some_and(|mime| mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON));
+    let is_text = mime.as_ref().is_some_and(|mime| {
+        mime.type_() == mime::TEXT
+            || mime.subtype() == mime::WWW_FORM_URLENCODED
+            || mime.subtype() == mime::XML
+            || mime.suffix() == Some(mime::XML)
+    });
+
+    let (encoding, content) = match (std::str::from_utf8(body), is_json) {
+        _ if body.is_empty() => ("text", Value::from("")),
+        (Ok(text), true) => match serde_json::from_str(text) {
+            Ok(json) => ("json", json),
+            Err(_) => ("text", Value::from(text)),
+        },
+        (Ok(text), false) if is_text => ("text", Value::from(text)),
+        _ => ("base64", Value::from(base64::engine::general_purpose::STANDARD.encode(body))),
+    };
+    json!({ "size": body.len(), "encoding": encoding, "content": content })
+}
+
+pub const ROUTES: &[Route] = &[
+    ("/sleep", &["GET"]),
+    ("/quit", &["GET"]),
+    ("/admin/shutdown", &["POST"]),
+    ("/diagnostics/server", &["GET"]),
+    ("/diagnostics/echo", ANY),
+];
+
 pub fn init_routes(config: &mut web::ServiceConfig) {
     config.service(sleep);
     config.service(quit);
+    config.service(admin_shutdown);
+    config.service(diagnostics);
+    config.service(web::resource("/diagnostics/echo").name("echo").route(web::route().to(echo)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    async fn call(req: test::TestRequest) -> (http::StatusCode, Value) {
+        let mut settings = Settings::default();
+        settings.diagnostics.echo_max_body = 32;
+        let app = test::init_service(App::new().app_data(web::Data::new(settings)).configure(init_routes)).await;
+        let res = test::call_service(&app, req.to_request()).await;
+        let status = res.status();
+        let body = test::read_body(res).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[actix_web::test]
+    async fn test_echo_reflects_request() {
+        let (status, echo) = call(
+            test::TestRequest::post()
+                .uri("/diagnostics/echo?tag=a&tag=b&page=1")
+                .peer_addr("10.0.0.5:4000".parse().unwrap())
+                .insert_header(("Authorization", "Bearer secret"))
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(r#"{"name": "Alice"}"#),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(echo["method"], "POST");
+        assert_eq!(echo["uri"], "http://localhost:8080/diagnostics/echo?tag=a&tag=b&page=1");
+        assert_eq!(echo["query"], json!({ "tag": ["a", "b"], "page": ["1"] }));
+        assert_eq!(echo["headers"]["authorization"], "[redacted]");
+        assert_eq!(echo["peer"]["client_ip"], "10.0.0.5");
+        assert_eq!(echo["route"], json!({ "pattern": "/diagnostics/echo", "name": "echo" }));
+        assert_eq!(echo["tls"], Value::Null);
+        assert_eq!(echo["body"], json!({ "size": 17, "encoding": "json", "content": { "name": "Alice" } }));
+
+        let (_, echo) = call(
+            test::TestRequest::put()
+                .uri("/diagnostics/echo")
+                .insert_header(("Content-Type", "application/octet-stream"))
+                .set_payload(&b"\x00\xff"[..]),
+        )
+        .await;
+        assert_eq!(echo["body"]["encoding"], "base64");
+        assert_eq!(echo["body"]["content"], "AP8=");
+
+        let (status, problem) = call(test::TestRequest::post().uri("/diagnostics/echo").set_payload(vec![b'a'; 33])).await;
+        assert_eq!(status, http::StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(problem["detail"], "request body exceeds 32 bytes");
+    }
+
+    #[actix_web::test]
+    async fn test_stats_reset_requires_admin_token() {
+        let stats = crate::stats::Stats::default();
+        let app = |token: Option<&str>| {
+            let mut settings = Settings::default();
+            settings.admin.token = token.map(String::from);
+            App::new()
+                .app_data(web::Data::new(Shutdown::new(&settings)))
+                .app_data(web::Data::new(stats.clone()))
+                .configure(crate::routes::extractor_routes)
+        };
+        let reset = |token: &str| {
+            test::TestRequest::post()
+                .uri("/stats/reset")
+                .insert_header((http::header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let disabled = test::init_service(app(None)).await;
+        assert_eq!(test::call_service(&disabled, reset("")).await.status(), http::StatusCode::NOT_FOUND);
+
+        let app = test::init_service(app(Some("secret"))).await;
+        let res = test::call_service(&app, reset("wrong")).await;
+        assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers().get(http::header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+        assert_eq!(test::call_service(&app, reset("secret")).await.status(), http::StatusCode::OK);
+    }
 }
@@ -4,6 +4,8 @@ use futures::stream;
 
 use std::task::Poll;
 
+use crate::routes::Route;
+
 #[derive(Serialize, Deserialize)]
 pub struct AppState {
     pub counter: i32,
@@ -42,6 +44,12 @@ async fn sse() -> HttpResponse {
         .streaming(server_events)
 }
 
+pub const ROUTES: &[Route] = &[
+    ("/testing", &["GET"]),
+    ("/testing/app-data", &["GET"]),
+    ("/testing/stream", &["GET"]),
+];
+
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     let counter = web::Data::new(AppState {
         counter: 3,
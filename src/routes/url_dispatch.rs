@@ -1,9 +1,16 @@
-use actix_web::{get, guard, http, web::{self, service}, HttpRequest, HttpResponse};
+#[allow(unused_imports)]
+use actix_web::{get, guard, http, web::{self, service}, HttpRequest, HttpResponse, Result};
 use serde::Deserialize;
+use validator::Validate;
 
-#[derive(Deserialize)]
+use crate::path_params::{ParamConfig, PathParams};
+use crate::routes::{Route, ANY};
+use crate::validation::{Validated, USERNAME};
+
+#[derive(Deserialize, Validate)]
 struct PathInfo {
     id: u32,
+    #[validate(length(max = 32), regex = "USERNAME")]
     username: String,
 }
 
@@ -16,17 +23,22 @@ async fn show_users() -> HttpResponse {
     HttpResponse::Ok().body("Show users")
 }
 
+// Users now live under `/users`; this keeps the old URL working. The location is
+// built by hand because a virtual host may mount this group without `users`, and
+// `url_for` would fail there.
 #[get("/show/{id}")]
-async fn user_detail(path: web::Path<(u32,)>) -> HttpResponse {
-    HttpResponse::Ok().body(format!("User detail: {}", path.into_inner().0))
+async fn user_detail(path: web::Path<(u64,)>) -> HttpResponse {
+    HttpResponse::PermanentRedirect()
+        .insert_header((http::header::LOCATION, format!("/users/{}", path.into_inner().0)))
+        .finish()
 }
 
 #[get("/match/{v1}/{v2}")]
-async fn match_info(req: HttpRequest) -> HttpResponse {
-    let v1: u8 = req.match_info().get("v1").unwrap().parse().unwrap();
-    let v2: u8 = req.match_info().query("v2").parse().unwrap();
-    let (v3, v4): (u8, u8) = req.match_info().load().unwrap();
-    HttpResponse::Ok().body(format!("Values {} {} {} {}", v1, v2, v3, v4))
+async fn match_info(req: HttpRequest) -> Result<HttpResponse> {
+    let v1: u8 = req.param("v1")?;
+    let v2: u8 = req.param("v2")?;
+    let (v3, v4): (u8, u8) = req.params()?;
+    Ok(HttpResponse::Ok().body(format!("Values {} {} {} {}", v1, v2, v3, v4)))
 }
 
 #[get("/path/{username}/{id}")]
@@ -36,7 +48,7 @@ async fn path_info(info: web::Path<(String, u32)>) -> HttpResponse {
 }
 
 #[get("/v2/path/{username}/{id}")]
-async fn path_info_v2(info: web::Path<PathInfo>) -> HttpResponse {
+async fn path_info_v2(info: Validated<web::Path<PathInfo>>) -> HttpResponse {
     HttpResponse::Ok().body(format!("Welcome {}! id: {}", info.username, info.id))
 }
 
@@ -57,6 +69,32 @@ async fn external_resources(req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().body(url.to_string())
 }
 
+// Parameter errors in the url-dispatch scope also name the pattern that matched.
+fn param_config() -> ParamConfig {
+    ParamConfig::default().error_handler(|err, req| {
+        err.problem()
+            .with_extension("pattern", req.match_pattern().unwrap_or_default())
+            .into()
+    })
+}
+
+pub const ROUTES: &[Route] = &[
+    ("/url-dispatch", &["GET"]),
+    ("/url-dispatch/user", &["POST"]),
+    ("/url-dispatch/prefix", ANY),
+    ("/url-dispatch/user/{name}", &["GET", "PUT"]),
+    ("/url-dispatch/path", &["GET"]),
+    ("/url-dispatch/show", &["GET"]),
+    ("/url-dispatch/show/{id}", &["GET"]),
+    ("/url-dispatch/match/{v1}/{v2}", &["GET"]),
+    ("/url-dispatch/path/{username}/{id}", &["GET"]),
+    ("/url-dispatch/v2/path/{username}/{id}", &["GET"]),
+    ("/url-dispatch/generate-resource-urls/{a}/{b}/{c}", &["GET"]),
+    ("/url-dispatch/generate-resource-url", &["GET"]),
+    ("/url-dispatch/external-resources", &["GET"]),
+    ("/url-dispatch/path-normalize", &["GET"]),
+];
+
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     // Resource configuration
     cfg.route("/url-dispatch", web::get().to(index));
@@ -64,7 +102,7 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/url-dispatch/prefix").to(index));
     cfg.service(
         web::resource("url-dispatch/user/{name}")
-            .name("user_detail")
+            .name("url_dispatch_user")
             .guard(guard::Header("content-type", "application/json"))
             .route(web::get().to(HttpResponse::Ok))
             .route(web::put().to(HttpResponse::Ok)),
@@ -81,6 +119,8 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         // Scoping Routes
         web::scope("url-dispatch")
+            .app_data(param_config())
+            .service(show_users)
             .service(user_detail)
             // Match information
             .service(match_info)
@@ -102,3 +142,18 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     );
     cfg.external_resource("youtube", "https://youtube.com/watch/{video_id}");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_show_users() {
+        let app = test::init_service(App::new().configure(init_routes)).await;
+        let req = test::TestRequest::get().uri("/url-dispatch/show").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "Show users");
+    }
+}
@@ -0,0 +1,221 @@
+use actix_web::{http, web, HttpRequest, HttpResponse, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::request_id;
+use crate::routes::Route;
+use crate::users::{NewUser, User, UserFilter, UserRepository};
+use crate::validation::Validated;
+
+const DEFAULT_PER_PAGE: u64 = 20;
+const MAX_PER_PAGE: u64 = 100;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListQuery {
+    page: Option<u64>,
+    per_page: Option<u64>,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UserList {
+    users: Vec<User>,
+    page: u64,
+    per_page: u64,
+    total: u64,
+}
+
+type Users = web::Data<dyn UserRepository>;
+
+// `page` starts at 1; `per_page` is capped at MAX_PER_PAGE.
+async fn list(repo: Users, query: web::Query<ListQuery>) -> Result<HttpResponse> {
+    let query = query.into_inner();
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let filter = UserFilter {
+        name: query.name.filter(|name| !name.is_empty()),
+        email: query.email.filter(|email| !email.is_empty()),
+        offset: (page - 1).saturating_mul(per_page),
+        limit: per_page,
+    };
+
+    let found = request_id::block(move || repo.list(&filter)).await??;
+    Ok(HttpResponse::Ok().json(UserList {
+        users: found.users,
+        page,
+        per_page,
+        total: found.total,
+    }))
+}
+
+async fn create(req: HttpRequest, repo: Users, user: Validated<web::Json<NewUser>>) -> Result<HttpResponse> {
+    let user = request_id::block(move || repo.create(user.into_inner().into_inner())).await??;
+    let location = req.url_for("user_detail", [user.id.to_string()])?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((http::header::LOCATION, location.as_str()))
+        .json(user))
+}
+
+async fn get(repo: Users, id: web::Path<u64>) -> Result<HttpResponse> {
+    let user = request_id::block(move || repo.get(id.into_inner())).await??;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn update(repo: Users, id: web::Path<u64>, user: Validated<web::Json<NewUser>>) -> Result<HttpResponse> {
+    let user = request_id::block(move || repo.update(id.into_inner(), user.into_inner().into_inner())).await??;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+async fn delete(repo: Users, id: web::Path<u64>) -> Result<HttpResponse> {
+    request_id::block(move || repo.delete(id.into_inner())).await??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub const ROUTES: &[Route] = &[("/users", &["GET", "POST"]), ("/users/{id}", &["GET", "PUT", "DELETE"])];
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/users")
+            .service(
+                web::resource("")
+                    .name("users")
+                    .route(web::get().to(list))
+                    .route(web::post().to(create)),
+            )
+            .service(
+                web::resource("/{id}")
+                    .name("user_detail")
+                    .route(web::get().to(get))
+                    .route(web::put().to(update))
+                    .route(web::delete().to(delete)),
+            ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::MemoryUsers;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_users_resource() {
+        let repo: Arc<dyn UserRepository> = Arc::new(MemoryUsers::default());
+        let app = test::init_service(App::new().app_data(Users::from(repo)).configure(init_routes)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({ "name": "Alice", "email": "alice@example.com" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::CREATED);
+        assert_eq!(res.headers().get(http::header::LOCATION).unwrap(), "http://localhost:8080/users/1");
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({ "name": "Alice again", "email": "alice@example.com" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::CONFLICT);
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({ "name": " ", "email": "not-an-email" }))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+        let body: Value = test::read_body_json(res).await;
+        let fields: Vec<_> = body["fields"].as_array().unwrap().iter().map(|f| f["field"].as_str().unwrap()).collect();
+        assert_eq!(fields, ["email", "name"]);
+
+        for name in ["Bob", "Bobby"] {
+            let email = format!("{}@example.com", name.to_lowercase());
+            let req = test::TestRequest::post()
+                .uri("/users")
+                .set_json(json!({ "name": name, "email": email }))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::get().uri("/users?name=bob&per_page=1&page=2").to_request();
+        let list: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(list["total"], 2);
+        assert_eq!(list["users"], json!([{ "id": 3, "name": "Bobby", "email": "bobby@example.com" }]));
+
+        let req = test::TestRequest::put()
+            .uri("/users/2")
+            .set_json(json!({ "name": "Robert", "email": "bob@example.com" }))
+            .to_request();
+        let user: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user["name"], "Robert");
+
+        let req = test::TestRequest::delete().uri("/users/2").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::NO_CONTENT);
+        let req = test::TestRequest::get().uri("/users/2").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_store_errors_carry_request_id() {
+        use crate::request_id::{RequestId, RequestIds, X_REQUEST_ID};
+        use crate::users::{UserError, UserPage};
+        use std::sync::Mutex;
+
+        // Fails every call, remembering the request ID it was called under.
+        #[derive(Default)]
+        struct Broken(Mutex<Option<String>>);
+
+        impl Broken {
+            fn fail<T>(&self) -> Result<T, UserError> {
+                *self.0.lock().unwrap() = RequestId::current().map(|id| id.to_string());
+                Err(UserError::Sqlite(rusqlite::Error::InvalidQuery))
+            }
+        }
+
+        impl UserRepository for Broken {
+            fn list(&self, _filter: &UserFilter) -> Result<UserPage, UserError> {
+                self.fail()
+            }
+            fn get(&self, _id: u64) -> Result<User, UserError> {
+                self.fail()
+            }
+            fn create(&self, _user: NewUser) -> Result<User, UserError> {
+                self.fail()
+            }
+            fn update(&self, _id: u64, _user: NewUser) -> Result<User, UserError> {
+                self.fail()
+            }
+            fn delete(&self, _id: u64) -> Result<(), UserError> {
+                self.fail()
+            }
+        }
+
+        let repo = Arc::new(Broken::default());
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestIds)
+                .app_data(Users::from(repo.clone() as Arc<dyn UserRepository>))
+                .configure(init_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/users/1").insert_header((X_REQUEST_ID, "store-7")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(repo.0.lock().unwrap().as_deref(), Some("store-7"));
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["request_id"], "store-7");
+    }
+
+    #[actix_web::test]
+    async fn test_legacy_url_redirects_without_users_routes() {
+        let app = test::init_service(App::new().configure(crate::routes::url_dispatch_routes)).await;
+        let req = test::TestRequest::get().uri("/url-dispatch/show/7").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers().get(http::header::LOCATION).unwrap(), "/users/7");
+    }
+}
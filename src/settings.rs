@@ -0,0 +1,993 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+const DEFAULT_CONFIG: &str = "config.toml";
+
+// (setting key, environment variable, command line flag)
+const OVERRIDES: &[(&str, &str, &str)] = &[
+    ("server.bind", "APP_BIND", "--bind"),
+    ("server.http_redirect", "APP_HTTP_REDIRECT", "--http-redirect"),
+    ("server.listen", "APP_LISTEN", "--listen"),
+    ("server.proxy_protocol", "APP_PROXY_PROTOCOL", "--proxy-protocol"),
+    ("server.trusted_proxies", "APP_TRUSTED_PROXIES", "--trusted-proxies"),
+    ("server.workers", "APP_WORKERS", "--workers"),
+    ("server.keep_alive", "APP_KEEP_ALIVE", "--keep-alive"),
+    ("server.client_request_timeout", "APP_CLIENT_REQUEST_TIMEOUT", "--client-request-timeout"),
+    ("server.client_disconnect_timeout", "APP_CLIENT_DISCONNECT_TIMEOUT", "--client-disconnect-timeout"),
+    ("server.max_connections", "APP_MAX_CONNECTIONS", "--max-connections"),
+    ("server.shutdown_timeout", "APP_SHUTDOWN_TIMEOUT", "--shutdown-timeout"),
+    ("tls.backend", "APP_TLS_BACKEND", "--tls-backend"),
+    ("tls.cert", "APP_TLS_CERT", "--tls-cert"),
+    ("tls.key", "APP_TLS_KEY", "--tls-key"),
+    ("tls.reload_interval", "APP_TLS_RELOAD_INTERVAL", "--tls-reload-interval"),
+    ("tls.client_auth", "APP_TLS_CLIENT_AUTH", "--tls-client-auth"),
+    ("tls.client_ca", "APP_TLS_CLIENT_CA", "--tls-client-ca"),
+    ("tls.hsts_max_age", "APP_TLS_HSTS_MAX_AGE", "--tls-hsts-max-age"),
+    ("tls.hsts_include_subdomains", "APP_TLS_HSTS_INCLUDE_SUBDOMAINS", "--tls-hsts-include-subdomains"),
+    ("log.level", "APP_LOG_LEVEL", "--log-level"),
+    ("log.access_format", "APP_LOG_ACCESS_FORMAT", "--log-access-format"),
+    ("log.access_fields", "APP_LOG_ACCESS_FIELDS", "--log-access-fields"),
+    ("log.redact_query", "APP_LOG_REDACT_QUERY", "--log-redact-query"),
+    ("log.redact_headers", "APP_LOG_REDACT_HEADERS", "--log-redact-headers"),
+    ("tracing.exporter", "APP_TRACING_EXPORTER", "--tracing-exporter"),
+    ("tracing.endpoint", "APP_TRACING_ENDPOINT", "--tracing-endpoint"),
+    ("tracing.file", "APP_TRACING_FILE", "--tracing-file"),
+    ("tracing.service_name", "APP_TRACING_SERVICE_NAME", "--tracing-service-name"),
+    ("counter.store", "APP_COUNTER_STORE", "--counter-store"),
+    ("counter.file", "APP_COUNTER_FILE", "--counter-file"),
+    ("counter.database", "APP_COUNTER_DATABASE", "--counter-database"),
+    ("users.store", "APP_USERS_STORE", "--users-store"),
+    ("users.database", "APP_USERS_DATABASE", "--users-database"),
+    ("vhosts.default", "APP_VHOSTS_DEFAULT", "--vhosts-default"),
+    ("diagnostics.echo_max_body", "APP_DIAGNOSTICS_ECHO_MAX_BODY", "--diagnostics-echo-max-body"),
+    ("diagnostics.echo_redact_headers", "APP_DIAGNOSTICS_ECHO_REDACT_HEADERS", "--diagnostics-echo-redact-headers"),
+    ("admin.token", "APP_ADMIN_TOKEN", "--admin-token"),
+];
+
+#[derive(Debug, derive_more::Display)]
+pub enum SettingsError {
+    #[display(fmt = "cannot read config file {}: {}", "path.display()", source)]
+    Read { path: PathBuf, source: io::Error },
+    #[display(fmt = "invalid config file {}: {}", "path.display()", source)]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[display(fmt = "invalid value {:?} for {}: {}", value, key, reason)]
+    Invalid { key: String, value: String, reason: String },
+    #[display(fmt = "unknown argument: {}", _0)]
+    UnknownArg(String),
+    #[display(fmt = "missing value for argument: {}", _0)]
+    MissingValue(String),
+}
+
+impl std::error::Error for SettingsError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub tls: TlsSettings,
+    pub log: LogSettings,
+    pub tracing: TracingSettings,
+    pub counter: CounterSettings,
+    pub users: UsersSettings,
+    pub vhosts: VhostSettings,
+    pub diagnostics: DiagnosticsSettings,
+    pub admin: AdminSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: Vec<String>,
+    pub http_redirect: Vec<String>,
+    pub listen: Vec<String>,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<String>,
+    pub workers: usize,
+    pub keep_alive: KeepAlive,
+    pub client_request_timeout: u64,
+    pub client_disconnect_timeout: u64,
+    pub max_connections: usize,
+    pub shutdown_timeout: u64,
+}
+
+// `keep_alive = 75`, `keep_alive = "os"` or `keep_alive = "disabled"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "KeepAliveValue")]
+pub enum KeepAlive {
+    Timeout(u64),
+    Os,
+    Disabled,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeepAliveValue {
+    Seconds(u64),
+    Name(String),
+}
+
+// Peers whose Forwarded and X-Forwarded-* headers are believed; `unix` stands for
+// connections on the Unix sockets in `server.listen`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustedProxy {
+    Unix,
+    Net(IpNet),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub backend: TlsBackend,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub reload_interval: u64,
+    pub client_auth: ClientAuth,
+    pub client_ca: Option<PathBuf>,
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, derive_more::Display)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsBackend {
+    #[display(fmt = "openssl")]
+    Openssl,
+    #[display(fmt = "rustls")]
+    Rustls,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    None,
+    Optional,
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: String,
+    pub access_format: AccessFormat,
+    pub access_fields: Vec<AccessField>,
+    pub redact_query: Vec<String>,
+    pub redact_headers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// Keys that can be written to a JSON access log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessField {
+    Time,
+    Method,
+    Path,
+    Pattern,
+    Status,
+    LatencyMs,
+    Bytes,
+    Peer,
+    TlsVersion,
+    RequestId,
+    Headers,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSettings {
+    pub exporter: TraceExporter,
+    pub endpoint: String,
+    pub file: PathBuf,
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    #[default]
+    None,
+    Otlp,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CounterSettings {
+    pub store: CounterStoreKind,
+    pub file: PathBuf,
+    pub database: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterStoreKind {
+    Memory,
+    #[default]
+    File,
+    Sqlite,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersSettings {
+    pub store: UserStoreKind,
+    pub database: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStoreKind {
+    Memory,
+    #[default]
+    Sqlite,
+}
+
+// Without any hosts every route group is served whatever the Host header says.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VhostSettings {
+    // Serves requests whose host matches no pattern; they get 404 when unset.
+    pub default: Option<String>,
+    pub hosts: Vec<VirtualHostSettings>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtualHostSettings {
+    pub name: String,
+    pub patterns: Vec<String>,
+    // Names of the route groups in `routes`, such as "application" or "users".
+    pub routes: Vec<String>,
+    pub static_root: Option<PathBuf>,
+}
+
+// A host name, `*.domain` for any subdomain of it, or `*` for every host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    Subdomain(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsSettings {
+    // Largest request body /diagnostics/echo reflects, in bytes.
+    pub echo_max_body: usize,
+    pub echo_redact_headers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub overrides: Vec<(&'static str, String)>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind: vec![String::from("127.0.0.1:8080")],
+            http_redirect: Vec::new(),
+            listen: Vec::new(),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            workers: 1,
+            keep_alive: KeepAlive::Timeout(5),
+            client_request_timeout: 5,
+            client_disconnect_timeout: 1,
+            max_connections: 25_000,
+            shutdown_timeout: 30,
+        }
+    }
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            backend: TlsBackend::default(),
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+            reload_interval: 0,
+            client_auth: ClientAuth::None,
+            client_ca: None,
+            hsts_max_age: 0,
+            hsts_include_subdomains: false,
+        }
+    }
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: String::from("info"),
+            access_format: AccessFormat::Text,
+            access_fields: AccessField::DEFAULT.to_vec(),
+            redact_query: ["access_token", "token", "password"].map(String::from).to_vec(),
+            redact_headers: ["authorization", "cookie", "proxy-authorization"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings {
+            exporter: TraceExporter::None,
+            endpoint: String::from("http://localhost:4318/v1/traces"),
+            file: PathBuf::from("spans.jsonl"),
+            service_name: String::from("actix_web"),
+        }
+    }
+}
+
+impl Default for CounterSettings {
+    fn default() -> Self {
+        CounterSettings {
+            store: CounterStoreKind::File,
+            file: PathBuf::from("counter.txt"),
+            database: PathBuf::from("counter.db"),
+        }
+    }
+}
+
+impl Default for UsersSettings {
+    fn default() -> Self {
+        UsersSettings {
+            store: UserStoreKind::Sqlite,
+            database: PathBuf::from("users.db"),
+        }
+    }
+}
+
+impl Default for DiagnosticsSettings {
+    fn default() -> Self {
+        DiagnosticsSettings {
+            echo_max_body: 64 * 1024,
+            echo_redact_headers: ["authorization", "cookie", "proxy-authorization", "x-api-key"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl TryFrom<KeepAliveValue> for KeepAlive {
+    type Error = String;
+
+    fn try_from(value: KeepAliveValue) -> Result<Self, Self::Error> {
+        match value {
+            KeepAliveValue::Seconds(secs) => Ok(KeepAlive::Timeout(secs)),
+            KeepAliveValue::Name(name) => name.parse(),
+        }
+    }
+}
+
+impl FromStr for KeepAlive {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "os" => Ok(KeepAlive::Os),
+            "disabled" => Ok(KeepAlive::Disabled),
+            secs => secs
+                .parse()
+                .map(KeepAlive::Timeout)
+                .map_err(|_| String::from("expected a number of seconds, os or disabled")),
+        }
+    }
+}
+
+impl From<KeepAlive> for actix_web::http::KeepAlive {
+    fn from(keep_alive: KeepAlive) -> Self {
+        match keep_alive {
+            KeepAlive::Timeout(secs) => std::time::Duration::from_secs(secs).into(),
+            KeepAlive::Os => actix_web::http::KeepAlive::Os,
+            KeepAlive::Disabled => actix_web::http::KeepAlive::Disabled,
+        }
+    }
+}
+
+impl Default for TlsBackend {
+    fn default() -> Self {
+        if cfg!(feature = "openssl") {
+            TlsBackend::Openssl
+        } else {
+            TlsBackend::Rustls
+        }
+    }
+}
+
+impl TlsBackend {
+    pub fn is_compiled_in(self) -> bool {
+        match self {
+            TlsBackend::Openssl => cfg!(feature = "openssl"),
+            TlsBackend::Rustls => cfg!(feature = "rustls"),
+        }
+    }
+}
+
+impl FromStr for TlsBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "openssl" => Ok(TlsBackend::Openssl),
+            "rustls" => Ok(TlsBackend::Rustls),
+            _ => Err(String::from("expected openssl or rustls")),
+        }
+    }
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(ClientAuth::None),
+            "optional" => Ok(ClientAuth::Optional),
+            "required" => Ok(ClientAuth::Required),
+            _ => Err(String::from("expected none, optional or required")),
+        }
+    }
+}
+
+impl FromStr for AccessFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(AccessFormat::Text),
+            "json" => Ok(AccessFormat::Json),
+            _ => Err(String::from("expected text or json")),
+        }
+    }
+}
+
+impl FromStr for TraceExporter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "file" => Ok(TraceExporter::File),
+            _ => Err(String::from("expected none, otlp or file")),
+        }
+    }
+}
+
+impl FromStr for CounterStoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(CounterStoreKind::Memory),
+            "file" => Ok(CounterStoreKind::File),
+            "sqlite" => Ok(CounterStoreKind::Sqlite),
+            _ => Err(String::from("expected memory, file or sqlite")),
+        }
+    }
+}
+
+impl FromStr for UserStoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "memory" => Ok(UserStoreKind::Memory),
+            "sqlite" => Ok(UserStoreKind::Sqlite),
+            _ => Err(String::from("expected memory or sqlite")),
+        }
+    }
+}
+
+impl AccessField {
+    // Request headers are left out by default; they are noisy even when redacted.
+    const DEFAULT: [AccessField; 10] = [
+        AccessField::Time,
+        AccessField::Method,
+        AccessField::Path,
+        AccessField::Pattern,
+        AccessField::Status,
+        AccessField::LatencyMs,
+        AccessField::Bytes,
+        AccessField::Peer,
+        AccessField::TlsVersion,
+        AccessField::RequestId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AccessField::Time => "time",
+            AccessField::Method => "method",
+            AccessField::Path => "path",
+            AccessField::Pattern => "pattern",
+            AccessField::Status => "status",
+            AccessField::LatencyMs => "latency_ms",
+            AccessField::Bytes => "bytes",
+            AccessField::Peer => "peer",
+            AccessField::TlsVersion => "tls_version",
+            AccessField::RequestId => "request_id",
+            AccessField::Headers => "headers",
+        }
+    }
+}
+
+impl FromStr for AccessField {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        AccessField::DEFAULT
+            .into_iter()
+            .chain([AccessField::Headers])
+            .find(|field| field.name() == value)
+            .ok_or_else(|| {
+                String::from(
+                    "expected a list of time, method, path, pattern, status, latency_ms, bytes, peer, tls_version, request_id or headers",
+                )
+            })
+    }
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, SettingsError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+                None => (arg.clone(), None),
+            };
+            let key = match OVERRIDES.iter().find(|(_, _, name)| *name == flag) {
+                Some((key, _, _)) => Some(*key),
+                None if flag == "--config" => None,
+                None => return Err(SettingsError::UnknownArg(arg)),
+            };
+            let value = match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(SettingsError::MissingValue(flag)),
+            };
+
+            match key {
+                Some(key) => parsed.overrides.push((key, value)),
+                None => parsed.config = Some(PathBuf::from(value)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl Settings {
+    // Layering order: defaults < config file < APP_* environment < command line.
+    pub fn load() -> Result<Self, SettingsError> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+        let args = Args::parse(std::env::args().skip(1))?;
+
+        let path = args
+            .config
+            .clone()
+            .or_else(|| env.get("APP_CONFIG").map(PathBuf::from))
+            .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG)).filter(|path| path.exists()));
+
+        let mut settings = match path {
+            Some(path) => Settings::from_file(&path)?,
+            None => Settings::default(),
+        };
+        settings.apply_env(&env)?;
+        settings.apply_args(&args)?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let source = fs::read_to_string(path).map_err(|source| SettingsError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&source).map_err(|source| SettingsError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub fn apply_env(&mut self, env: &HashMap<String, String>) -> Result<(), SettingsError> {
+        for (key, var, _) in OVERRIDES {
+            if let Some(value) = env.get(*var) {
+                self.set(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_args(&mut self, args: &Args) -> Result<(), SettingsError> {
+        for (key, value) in &args.overrides {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingsError> {
+        match key {
+            "server.bind" => self.server.bind = parse_list(value),
+            "server.http_redirect" => self.server.http_redirect = parse_list(value),
+            "server.listen" => self.server.listen = parse_list(value),
+            "server.proxy_protocol" => self.server.proxy_protocol = parse(key, value)?,
+            "server.trusted_proxies" => self.server.trusted_proxies = parse_list(value),
+            "server.workers" => self.server.workers = parse(key, value)?,
+            "server.keep_alive" => self.server.keep_alive = parse(key, value)?,
+            "server.client_request_timeout" => self.server.client_request_timeout = parse(key, value)?,
+            "server.client_disconnect_timeout" => self.server.client_disconnect_timeout = parse(key, value)?,
+            "server.max_connections" => self.server.max_connections = parse(key, value)?,
+            "server.shutdown_timeout" => self.server.shutdown_timeout = parse(key, value)?,
+            "tls.backend" => self.tls.backend = parse(key, value)?,
+            "tls.cert" => self.tls.cert = PathBuf::from(value),
+            "tls.key" => self.tls.key = PathBuf::from(value),
+            "tls.reload_interval" => self.tls.reload_interval = parse(key, value)?,
+            "tls.client_auth" => self.tls.client_auth = parse(key, value)?,
+            "tls.client_ca" => self.tls.client_ca = Some(PathBuf::from(value)),
+            "tls.hsts_max_age" => self.tls.hsts_max_age = parse(key, value)?,
+            "tls.hsts_include_subdomains" => self.tls.hsts_include_subdomains = parse(key, value)?,
+            "log.level" => self.log.level = value.to_owned(),
+            "log.access_format" => self.log.access_format = parse(key, value)?,
+            "log.access_fields" => {
+                self.log.access_fields = parse_list(value)
+                    .iter()
+                    .map(|field| parse(key, field))
+                    .collect::<Result<_, _>>()?
+            }
+            "log.redact_query" => self.log.redact_query = parse_list(value),
+            "log.redact_headers" => self.log.redact_headers = parse_list(value),
+            "tracing.exporter" => self.tracing.exporter = parse(key, value)?,
+            "tracing.endpoint" => self.tracing.endpoint = value.to_owned(),
+            "tracing.file" => self.tracing.file = PathBuf::from(value),
+            "tracing.service_name" => self.tracing.service_name = value.to_owned(),
+            "counter.store" => self.counter.store = parse(key, value)?,
+            "counter.file" => self.counter.file = PathBuf::from(value),
+            "counter.database" => self.counter.database = PathBuf::from(value),
+            "users.store" => self.users.store = parse(key, value)?,
+            "users.database" => self.users.database = PathBuf::from(value),
+            "vhosts.default" => self.vhosts.default = Some(value.to_owned()).filter(|name| !name.is_empty()),
+            "diagnostics.echo_max_body" => self.diagnostics.echo_max_body = parse(key, value)?,
+            "diagnostics.echo_redact_headers" => self.diagnostics.echo_redact_headers = parse_list(value),
+            "admin.token" => self.admin.token = Some(value.to_owned()).filter(|token| !token.is_empty()),
+            _ => return Err(invalid(key, value, "unknown setting")),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.server.bind.is_empty() && self.server.listen.is_empty() {
+            return Err(invalid("server.bind", "", "at least one address is required unless server.listen is set"));
+        }
+        for (key, addrs) in [("server.bind", &self.server.bind), ("server.http_redirect", &self.server.http_redirect)] {
+            if let Some(addr) = addrs.iter().find(|addr| port(addr).is_none()) {
+                return Err(invalid(key, addr, "expected HOST:PORT"));
+            }
+        }
+        if let Some(addr) = self.server.listen.iter().find(|addr| unix_path(addr).is_none()) {
+            return Err(invalid("server.listen", addr, "expected unix:PATH"));
+        }
+        if let (false, Some(addr)) = (cfg!(unix), self.server.listen.first()) {
+            return Err(invalid("server.listen", addr, "Unix sockets are only supported on unix"));
+        }
+        if let Some(proxy) = self.server.trusted_proxies.iter().find(|proxy| trusted_proxy(proxy).is_none()) {
+            return Err(invalid("server.trusted_proxies", proxy, "expected an IP address, a CIDR range or unix"));
+        }
+        self.vhosts.validate()?;
+        if self.server.workers == 0 {
+            return Err(invalid("server.workers", "0", "must be at least 1"));
+        }
+        if self.server.max_connections == 0 {
+            return Err(invalid("server.max_connections", "0", "must be at least 1"));
+        }
+        if !self.tls.backend.is_compiled_in() {
+            let reason = format!("this binary was built without the `{}` feature", self.tls.backend);
+            return Err(invalid("tls.backend", &self.tls.backend.to_string(), &reason));
+        }
+        if self.tls.client_auth != ClientAuth::None && self.tls.client_ca.is_none() {
+            return Err(invalid("tls.client_ca", "", "required when tls.client_auth is enabled"));
+        }
+        if self.admin.token.as_deref() == Some("") {
+            return Err(invalid("admin.token", "", "must not be empty; leave it unset to disable the admin endpoint"));
+        }
+        // Same directive forms as env_logger: `level`, `module` or `module=level`,
+        // optionally followed by `/regex`.
+        let spec = self.log.level.split_once('/').map_or(self.log.level.as_str(), |(spec, _)| spec);
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let valid = match directive.split_once('=') {
+                Some((module, level)) => is_module_path(module) && log::LevelFilter::from_str(level).is_ok(),
+                None => log::LevelFilter::from_str(directive).is_ok() || is_module_path(directive),
+            };
+            if !valid {
+                return Err(invalid("log.level", &self.log.level, "expected a level such as info or debug"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServerSettings {
+    // Port that plain HTTP requests are redirected to.
+    pub fn https_port(&self) -> u16 {
+        self.bind.first().and_then(|addr| port(addr)).unwrap_or(443)
+    }
+
+    pub fn unix_sockets(&self) -> impl Iterator<Item = &Path> {
+        self.listen.iter().filter_map(|addr| unix_path(addr))
+    }
+
+    pub fn trusted_proxies(&self) -> Vec<TrustedProxy> {
+        self.trusted_proxies.iter().filter_map(|proxy| trusted_proxy(proxy)).collect()
+    }
+}
+
+impl VhostSettings {
+    fn validate(&self) -> Result<(), SettingsError> {
+        for (idx, host) in self.hosts.iter().enumerate() {
+            if host.name.is_empty() || self.hosts[..idx].iter().any(|other| other.name == host.name) {
+                return Err(invalid("vhosts.hosts.name", &host.name, "host names must be unique and not empty"));
+            }
+            if let Some(pattern) = host.patterns.iter().find(|pattern| host_pattern(pattern).is_none()) {
+                return Err(invalid("vhosts.hosts.patterns", pattern, "expected a host name, *.domain or *"));
+            }
+        }
+        match &self.default {
+            Some(name) if !self.hosts.iter().any(|host| &host.name == name) => {
+                Err(invalid("vhosts.default", name, "no host in vhosts.hosts has this name"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl VirtualHostSettings {
+    pub fn patterns(&self) -> Vec<HostPattern> {
+        self.patterns.iter().filter_map(|pattern| host_pattern(pattern)).collect()
+    }
+}
+
+fn port(addr: &str) -> Option<u16> {
+    addr.rsplit_once(':').and_then(|(_, port)| port.parse().ok())
+}
+
+fn trusted_proxy(value: &str) -> Option<TrustedProxy> {
+    match value {
+        "unix" => Some(TrustedProxy::Unix),
+        value => value
+            .parse()
+            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            .ok()
+            .map(TrustedProxy::Net),
+    }
+}
+
+// Host names compare case-insensitively and without the port.
+fn host_pattern(value: &str) -> Option<HostPattern> {
+    let value = value.trim().to_ascii_lowercase();
+    let valid = |name: &str| !name.is_empty() && !name.contains(['*', ':', '/']);
+    match value.strip_prefix("*.") {
+        _ if value == "*" => Some(HostPattern::Any),
+        Some(domain) => Some(HostPattern::Subdomain(domain.to_owned())).filter(|_| valid(domain)),
+        None => Some(HostPattern::Exact(value.clone())).filter(|_| valid(&value)),
+    }
+}
+
+fn unix_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix("unix:").filter(|path| !path.is_empty()).map(Path::new)
+}
+
+fn is_module_path(path: &str) -> bool {
+    path.split("::").all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, SettingsError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|err: T::Err| invalid(key, value, &err.to_string()))
+}
+
+fn invalid(key: &str, value: &str, reason: &str) -> SettingsError {
+    SettingsError::Invalid {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let settings = Settings::default();
+        assert!(settings.validate().is_ok());
+        assert_eq!(settings.server.bind, vec!["127.0.0.1:8080"]);
+        assert_eq!(settings.server.workers, 1);
+    }
+
+    #[test]
+    fn test_file_env_and_args_layering() {
+        let mut settings: Settings = toml::from_str(
+            r#"
+            [server]
+            bind = ["0.0.0.0:8443"]
+            workers = 4
+
+            [log]
+            level = "debug"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.tls, TlsSettings::default());
+
+        let env = HashMap::from([
+            ("APP_WORKERS".to_owned(), "8".to_owned()),
+            ("APP_TLS_CERT".to_owned(), "/etc/app/cert.pem".to_owned()),
+        ]);
+        settings.apply_env(&env).unwrap();
+        settings.apply_args(&args(&["--workers", "2", "--bind=127.0.0.1:1,127.0.0.1:2"])).unwrap();
+        settings.validate().unwrap();
+
+        assert_eq!(settings.server.workers, 2);
+        assert_eq!(settings.server.bind, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(settings.tls.cert, PathBuf::from("/etc/app/cert.pem"));
+        assert_eq!(settings.log.level, "debug");
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let mut settings = Settings::default();
+        let err = settings.set("server.workers", "many").unwrap_err();
+        assert!(err.to_string().contains("server.workers"));
+
+        settings.set("server.workers", "0").unwrap();
+        assert!(settings.validate().is_err());
+
+        let mut settings = Settings::default();
+        settings.set("server.bind", "localhost").unwrap();
+        assert!(settings.validate().is_err());
+
+        let mut settings = Settings::default();
+        settings.set("server.listen", "/run/app.sock").unwrap();
+        assert!(settings.validate().is_err());
+        settings.set("server.listen", "unix:/run/app.sock").unwrap();
+        settings.set("server.bind", "").unwrap();
+        settings.validate().unwrap();
+        assert_eq!(settings.server.unix_sockets().collect::<Vec<_>>(), [Path::new("/run/app.sock")]);
+
+        let mut settings = Settings::default();
+        settings.set("server.trusted_proxies", "10.0.0.0/8, 192.168.1.7, unix").unwrap();
+        settings.validate().unwrap();
+        assert_eq!(settings.server.trusted_proxies().len(), 3);
+        settings.set("server.trusted_proxies", "10.0.0.0/33").unwrap();
+        assert!(settings.validate().is_err());
+
+        let mut settings = Settings::default();
+        settings.set("log.level", "actix_web=loud").unwrap();
+        assert!(settings.validate().is_err());
+        for level in ["actix_web", "info,actix_server", "warn,actix_web::middleware=debug/GET"] {
+            settings.set("log.level", level).unwrap();
+            settings.validate().unwrap();
+        }
+
+        assert!(Settings::default().set("server.keep_alive", "forever").is_err());
+        assert!(Settings::default().set("log.access_fields", "method,cookies").is_err());
+    }
+
+    #[test]
+    fn test_keep_alive_forms() {
+        let parse = |source: &str| toml::from_str::<Settings>(source).map(|settings| settings.server.keep_alive);
+        assert_eq!(parse("[server]\nkeep_alive = 75").unwrap(), KeepAlive::Timeout(75));
+        assert_eq!(parse("[server]\nkeep_alive = \"os\"").unwrap(), KeepAlive::Os);
+        assert_eq!(parse("[server]\nkeep_alive = \"disabled\"").unwrap(), KeepAlive::Disabled);
+        assert!(parse("[server]\nkeep_alive = \"sometimes\"").is_err());
+
+        let mut settings = Settings::default();
+        settings.set("server.keep_alive", "os").unwrap();
+        assert_eq!(settings.server.keep_alive, KeepAlive::Os);
+    }
+
+    #[test]
+    fn test_vhost_settings() {
+        let mut settings: Settings = toml::from_str(
+            r#"
+            [vhosts]
+            default = "main"
+
+            [[vhosts.hosts]]
+            name = "main"
+            routes = ["application"]
+
+            [[vhosts.hosts]]
+            name = "users"
+            patterns = ["Users.Example.test", "*.users.example.test"]
+            routes = ["users"]
+            static_root = "static"
+            "#,
+        )
+        .unwrap();
+        settings.validate().unwrap();
+        assert_eq!(
+            settings.vhosts.hosts[1].patterns(),
+            [
+                HostPattern::Exact(String::from("users.example.test")),
+                HostPattern::Subdomain(String::from("users.example.test"))
+            ]
+        );
+
+        settings.set("vhosts.default", "www").unwrap();
+        assert!(settings.validate().is_err());
+        settings.set("vhosts.default", "").unwrap();
+        settings.validate().unwrap();
+
+        for pattern in ["*.", "example.test:8080", "www.*.test"] {
+            settings.vhosts.hosts[1].patterns = vec![String::from(pattern)];
+            assert!(settings.validate().is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_access_log_settings() {
+        let mut settings: Settings = toml::from_str(
+            r#"
+            [log]
+            access_format = "json"
+            access_fields = ["method", "status", "headers"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.log.access_format, AccessFormat::Json);
+        assert_eq!(settings.log.access_fields, [AccessField::Method, AccessField::Status, AccessField::Headers]);
+        assert!(settings.log.redact_headers.contains(&String::from("authorization")));
+
+        settings.apply_args(&args(&["--log-access-fields", "pattern, latency_ms", "--log-redact-query", "sig"])).unwrap();
+        assert_eq!(settings.log.access_fields, [AccessField::Pattern, AccessField::LatencyMs]);
+        assert_eq!(settings.log.redact_query, vec!["sig"]);
+    }
+
+    #[test]
+    fn test_unknown_args_and_fields() {
+        assert!(Args::parse(vec!["--nope".to_owned(), "1".to_owned()]).is_err());
+        assert!(matches!(Args::parse(vec!["--nope".to_owned()]), Err(SettingsError::UnknownArg(_))));
+        assert!(matches!(Args::parse(vec!["--workers".to_owned()]), Err(SettingsError::MissingValue(_))));
+        assert!(toml::from_str::<Settings>("[server]\nthreads = 2").is_err());
+    }
+}
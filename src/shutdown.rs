@@ -0,0 +1,376 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, ServerHandle, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use log::{info, warn};
+use pin_project_lite::pin_project;
+use tokio::sync::Notify;
+
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+// Shared between the signal task, the admin endpoint and anything that needs to
+// know whether the server is draining.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    draining: AtomicBool,
+    reason: Mutex<Option<String>>,
+    notify: Notify,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    timeout: Duration,
+    token: Option<String>,
+}
+
+impl Shutdown {
+    pub fn new(settings: &Settings) -> Self {
+        Shutdown {
+            inner: Arc::new(Inner {
+                draining: AtomicBool::new(false),
+                reason: Mutex::new(None),
+                notify: Notify::new(),
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+                timeout: Duration::from_secs(settings.server.shutdown_timeout),
+                token: settings.admin.token.clone(),
+            }),
+        }
+    }
+
+    pub fn trigger(&self, reason: &str) {
+        let mut current = self.inner.reason.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if current.is_none() {
+            *current = Some(reason.to_owned());
+        }
+        self.inner.draining.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_one();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.token.is_some()
+    }
+
+    pub fn authorize(&self, token: &str) -> bool {
+        match &self.inner.token {
+            Some(expected) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            if self.inner.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    // Waits for SIGTERM, SIGINT or `trigger`, then stops accepting connections and
+    // gives in-flight requests up to `server.shutdown_timeout` to finish. A second
+    // signal while draining stops the server immediately.
+    //
+    // Requests are drained here rather than by `stop(true)` alone: actix-server
+    // stops the accept thread before it tells workers to stop, and a worker that
+    // notices the closed accept channel first exits without waiting for its
+    // connections.
+    pub fn spawn(self, handle: ServerHandle) {
+        actix_web::rt::spawn(async move {
+            let mut signals = Signals::new();
+
+            tokio::select! {
+                signal = signals.recv() => self.trigger(signal),
+                _ = self.inner.notify.notified() => {}
+            }
+
+            let reason = self.inner.reason.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+            info!(
+                "shutdown requested ({}); draining in-flight requests for up to {}s",
+                reason.unwrap_or_default(),
+                self.inner.timeout.as_secs()
+            );
+
+            handle.pause().await;
+            tokio::select! {
+                drained = tokio::time::timeout(self.inner.timeout, self.wait_idle()) => match drained {
+                    Ok(()) => handle.stop(true).await,
+                    Err(_) => {
+                        warn!("requests still in flight after {}s; stopping", self.inner.timeout.as_secs());
+                        handle.stop(false).await;
+                    }
+                },
+                signal = signals.recv() => {
+                    warn!("{} received while draining; stopping immediately", signal);
+                    handle.stop(false).await;
+                }
+            }
+        });
+    }
+}
+
+// Counts requests in flight so `spawn` knows when draining is done. A request
+// counts until its response body has been sent or dropped, so streamed bodies
+// are drained too.
+impl<S, B> Transform<S, ServiceRequest> for Shutdown
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InFlightBody<B>>;
+    type Error = Error;
+    type Transform = ShutdownMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ShutdownMiddleware {
+            service: Rc::new(service),
+            shutdown: self.clone(),
+        }))
+    }
+}
+
+pub struct ShutdownMiddleware<S> {
+    service: Rc<S>,
+    shutdown: Shutdown,
+}
+
+impl<S, B> Service<ServiceRequest> for ShutdownMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InFlightBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let in_flight = InFlight::start(&self.shutdown);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res.map_body(|_, body| InFlightBody { body, _in_flight: in_flight }))
+        })
+    }
+}
+
+pin_project! {
+    // Holds the request's place in the in-flight count until the body is dropped.
+    pub struct InFlightBody<B> {
+        #[pin]
+        body: B,
+        _in_flight: InFlight,
+    }
+}
+
+impl<B: MessageBody> MessageBody for InFlightBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.project().body.poll_next(cx)
+    }
+}
+
+struct InFlight(Arc<Inner>);
+
+impl InFlight {
+    fn start(shutdown: &Shutdown) -> Self {
+        shutdown.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(&shutdown.inner))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+struct Signals {
+    #[cfg(unix)]
+    term: Option<tokio::signal::unix::Signal>,
+    #[cfg(unix)]
+    int: Option<tokio::signal::unix::Signal>,
+}
+
+impl Signals {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Signals {
+                term: signal(SignalKind::terminate()).ok(),
+                int: signal(SignalKind::interrupt()).ok(),
+            }
+        }
+        #[cfg(not(unix))]
+        Signals {}
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) -> &'static str {
+        async fn recv(signal: &mut Option<tokio::signal::unix::Signal>) {
+            match signal {
+                Some(signal) => {
+                    signal.recv().await;
+                }
+                None => futures::future::pending().await,
+            }
+        }
+
+        tokio::select! {
+            _ = recv(&mut self.term) => "SIGTERM",
+            _ = recv(&mut self.int) => "SIGINT",
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> &'static str {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use actix_web::{get, web, App, HttpServer, Responder};
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    static SLOW_STARTED: AtomicBool = AtomicBool::new(false);
+
+    #[get("/slow")]
+    async fn slow() -> impl Responder {
+        SLOW_STARTED.store(true, Ordering::SeqCst);
+        actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+        "done"
+    }
+
+    async fn request(port: u16, request: String) -> std::io::Result<String> {
+        actix_web::rt::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+            stream.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        })
+        .await
+        .unwrap()
+    }
+
+    fn get(path: &str) -> String {
+        format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)
+    }
+
+    fn post(path: &str, token: &str) -> String {
+        format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            path, token
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_slow_request_finishes_during_drain() {
+        let mut settings = Settings::default();
+        settings.server.shutdown_timeout = 5;
+        settings.admin.token = Some(String::from("secret"));
+        let shutdown = Shutdown::new(&settings);
+
+        let data = web::Data::new(shutdown.clone());
+        let drain = shutdown.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .wrap(drain.clone())
+                .service(slow)
+                .configure(routes::server_routes)
+        })
+        .workers(1)
+        .disable_signals()
+        .shutdown_timeout(settings.server.shutdown_timeout)
+        .listen(listener)
+        .unwrap()
+        .run();
+        shutdown.clone().spawn(server.handle());
+        let server = actix_web::rt::spawn(server);
+
+        let in_flight = actix_web::rt::spawn(request(port, get("/slow")));
+        while !SLOW_STARTED.load(Ordering::SeqCst) {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let denied = request(port, post("/admin/shutdown", "wrong")).await.unwrap();
+        assert!(denied.starts_with("HTTP/1.1 401"), "{}", denied);
+        assert!(!shutdown.is_draining());
+
+        let accepted = request(port, post("/admin/shutdown", "secret")).await.unwrap();
+        assert!(accepted.starts_with("HTTP/1.1 202"), "{}", accepted);
+        assert!(shutdown.is_draining());
+
+        let response = in_flight.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("done"));
+
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_streamed_body_counts_until_sent() {
+        let shutdown = Shutdown::new(&Settings::default());
+        let app = actix_web::test::init_service(App::new().wrap(shutdown.clone()).route(
+            "/stream",
+            web::get().to(|| async {
+                let chunks = futures::stream::iter([Ok::<_, Error>(Bytes::from("a")), Ok(Bytes::from("b"))]);
+                actix_web::HttpResponse::Ok().streaming(chunks)
+            }),
+        ))
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri("/stream").to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(shutdown.inner.in_flight.load(Ordering::SeqCst), 1);
+        assert_eq!(actix_web::test::read_body(res).await, "ab");
+        assert_eq!(shutdown.inner.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_without_token() {
+        let shutdown = Shutdown::new(&Settings::default());
+        assert!(!shutdown.is_enabled());
+        assert!(!shutdown.authorize(""));
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+// Named counters kept separately by every worker thread. Handlers count through
+// their worker's `WorkerStats`; `Stats` sees all workers and adds them up.
+#[derive(Clone, Default)]
+pub struct Stats {
+    workers: Arc<Mutex<HashMap<ThreadId, Arc<WorkerStats>>>>,
+}
+
+pub struct WorkerStats {
+    id: usize,
+    thread: Option<String>,
+    counters: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub total: BTreeMap<&'static str, u64>,
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerSnapshot {
+    pub id: usize,
+    pub thread: Option<String>,
+    pub counters: BTreeMap<&'static str, u64>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Stats {
+    // Counters of the calling thread. The app factory runs once per worker and
+    // listener, so it is keyed by thread and the same worker always gets the same ID.
+    pub fn worker(&self) -> Arc<WorkerStats> {
+        let mut workers = lock(&self.workers);
+        let id = workers.len();
+        let worker = workers.entry(thread::current().id()).or_insert_with(|| {
+            Arc::new(WorkerStats {
+                id,
+                thread: thread::current().name().map(String::from),
+                counters: Mutex::default(),
+            })
+        });
+        Arc::clone(worker)
+    }
+
+    pub fn total(&self, name: &str) -> u64 {
+        lock(&self.workers).values().map(|worker| worker.get(name)).sum()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut workers: Vec<WorkerSnapshot> = lock(&self.workers)
+            .values()
+            .map(|worker| WorkerSnapshot {
+                id: worker.id,
+                thread: worker.thread.clone(),
+                counters: lock(&worker.counters).clone(),
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.id);
+
+        let mut total = BTreeMap::new();
+        for (name, value) in workers.iter().flat_map(|worker| &worker.counters) {
+            *total.entry(*name).or_default() += value;
+        }
+        Snapshot { total, workers }
+    }
+
+    // Sets one counter, or all of them, back to zero on every worker.
+    pub fn reset(&self, name: Option<&str>) {
+        for worker in lock(&self.workers).values() {
+            let mut counters = lock(&worker.counters);
+            match name {
+                Some(name) => {
+                    if let Some(value) = counters.get_mut(name) {
+                        *value = 0;
+                    }
+                }
+                None => counters.values_mut().for_each(|value| *value = 0),
+            }
+        }
+    }
+}
+
+impl WorkerStats {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    // Adds one and returns this worker's new value.
+    pub fn increment(&self, name: &'static str) -> u64 {
+        let mut counters = lock(&self.counters);
+        let value = counters.entry(name).or_default();
+        *value += 1;
+        *value
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        lock(&self.counters).get(name).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workers_are_counted_separately_and_together() {
+        let stats = Stats::default();
+        let main = stats.worker();
+        assert!(Arc::ptr_eq(&main, &stats.worker()));
+        main.increment("hits");
+        main.increment("hits");
+
+        let other = stats.clone();
+        thread::Builder::new()
+            .name(String::from("worker-b"))
+            .spawn(move || {
+                let worker = other.worker();
+                assert_eq!(worker.id(), 1);
+                assert_eq!(worker.increment("hits"), 1);
+                worker.increment("misses");
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(main.get("hits"), 2);
+        assert_eq!(stats.total("hits"), 3);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total, BTreeMap::from([("hits", 3), ("misses", 1)]));
+        assert_eq!(snapshot.workers[1].thread.as_deref(), Some("worker-b"));
+        assert_eq!(snapshot.workers[1].counters["misses"], 1);
+
+        stats.reset(Some("hits"));
+        assert_eq!(stats.total("hits"), 0);
+        assert_eq!(stats.total("misses"), 1);
+        stats.reset(None);
+        assert_eq!(stats.total("misses"), 0);
+    }
+}
@@ -0,0 +1,340 @@
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ready, BoxFuture, LocalBoxFuture, Ready};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanId, Status, TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use serde_json::{json, Map, Value};
+use tracing::field::{Empty, Field, Visit};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use crate::request_id::RequestId;
+use crate::settings::{TraceExporter, TracingSettings};
+
+// Installed exporter; spans still queued are flushed by `shutdown`.
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    // Sets up the global `tracing` subscriber: spans become OpenTelemetry spans when an
+    // exporter is configured, and events, which actix-server logs through, go to `log`.
+    pub fn init(settings: &TracingSettings) -> Result<Self, TraceError> {
+        let builder = TracerProvider::builder().with_config(
+            opentelemetry_sdk::trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", settings.service_name.clone())])),
+        );
+        let provider = match settings.exporter {
+            TraceExporter::None => None,
+            TraceExporter::Otlp => {
+                let http = opentelemetry_otlp::new_exporter().http().with_endpoint(&settings.endpoint);
+                let exporter = SpanExporterBuilder::from(http).build_span_exporter()?;
+                Some(builder.with_batch_exporter(exporter, runtime::TokioCurrentThread).build())
+            }
+            TraceExporter::File => {
+                let exporter = FileExporter::create(&settings.file)
+                    .map_err(|err| TraceError::from(format!("cannot open {}: {}", settings.file.display(), err)))?;
+                Some(builder.with_simple_exporter(exporter).build())
+            }
+        };
+
+        let layer = provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("actix_web")));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(LogEvents).with(layer))
+            .map_err(|err| TraceError::from(err.to_string()))?;
+        Ok(Telemetry { provider })
+    }
+
+    pub fn shutdown(self) {
+        for result in self.provider.iter().flat_map(TracerProvider::force_flush) {
+            if let Err(err) = result {
+                log::warn!("failed to export spans: {}", err);
+            }
+        }
+    }
+}
+
+// Forwards `tracing` events to `log`, so they keep going through env_logger.
+struct LogEvents;
+
+impl<S: tracing::Subscriber> Layer<S> for LogEvents {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: layer::Context<'_, S>) {
+        let meta = event.metadata();
+        let level = match *meta.level() {
+            tracing::Level::ERROR => log::Level::Error,
+            tracing::Level::WARN => log::Level::Warn,
+            tracing::Level::INFO => log::Level::Info,
+            tracing::Level::DEBUG => log::Level::Debug,
+            tracing::Level::TRACE => log::Level::Trace,
+        };
+        if !log::log_enabled!(target: meta.target(), level) {
+            return;
+        }
+
+        let mut message = EventMessage(String::new());
+        event.record(&mut message);
+        log::logger().log(
+            &log::Record::builder()
+                .level(level)
+                .target(meta.target())
+                .module_path(meta.module_path())
+                .file(meta.file())
+                .line(meta.line())
+                .args(format_args!("{}", message.0))
+                .build(),
+        );
+    }
+}
+
+struct EventMessage(String);
+
+impl Visit for EventMessage {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = match field.name() {
+            "message" => write!(self.0, "{:?}", value),
+            name => write!(self.0, "{}={:?}", name, value),
+        };
+    }
+}
+
+// Opens a root span for every request, continuing the caller's trace when it
+// sends a W3C `traceparent` header, and returns `traceparent` on the response.
+#[derive(Clone, Default)]
+pub struct Tracing;
+
+impl<S, B> Transform<S, ServiceRequest> for Tracing
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TracingMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for TracingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let method = req.method().to_string();
+        // The route pattern is only known after routing, so `otel.name` starts out
+        // as the method alone and is filled in from the response.
+        let span = tracing::info_span!(
+            "HTTP request",
+            otel.name = %method,
+            otel.kind = "server",
+            otel.status_code = Empty,
+            http.method = %method,
+            http.target = %req.path(),
+            http.route = Empty,
+            http.status_code = Empty,
+            request_id = Empty,
+        );
+        span.set_parent(TraceContextPropagator::new().extract(&Headers(req.headers())));
+
+        let fut = span.in_scope(|| self.service.call(req));
+        Box::pin(async move {
+            let res = fut.instrument(span.clone()).await;
+
+            let status = match &res {
+                Ok(res) => {
+                    if let Some(pattern) = res.request().match_pattern() {
+                        span.record("otel.name", format!("{} {}", method, pattern));
+                        span.record("http.route", pattern);
+                    }
+                    if let Some(id) = res.request().extensions().get::<RequestId>() {
+                        span.record("request_id", id.as_str());
+                    }
+                    res.status()
+                }
+                Err(err) => err.as_response_error().status_code(),
+            };
+            span.record("http.status_code", status.as_u16());
+            if status.is_server_error() {
+                span.record("otel.status_code", "ERROR");
+            }
+
+            res.map(|mut res| {
+                TraceContextPropagator::new().inject_context(&span.context(), &mut HeadersMut(res.headers_mut()));
+                res
+            })
+        })
+    }
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeadersMut<'a>(&'a mut HeaderMap);
+
+impl Injector for HeadersMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+// Appends finished spans to a file, one JSON object per line. Meant for local
+// debugging and tests rather than production use.
+#[derive(Debug)]
+pub struct FileExporter {
+    writer: BufWriter<File>,
+}
+
+impl FileExporter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileExporter {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    fn write(&mut self, spans: Vec<SpanData>) -> io::Result<()> {
+        for span in spans {
+            serde_json::to_writer(&mut self.writer, &span_json(&span))?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let result = self.write(batch).map_err(|err| TraceError::from(err.to_string()));
+        Box::pin(ready(result))
+    }
+}
+
+fn span_json(span: &SpanData) -> Value {
+    let nanos = |time: std::time::SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    let attributes: Map<String, Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::from(value.as_str().into_owned())))
+        .collect();
+    let status = match &span.status {
+        Status::Unset => Value::from("unset"),
+        Status::Ok => Value::from("ok"),
+        Status::Error { description } => json!({ "error": description }),
+    };
+
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": (span.parent_span_id != SpanId::INVALID).then(|| span.parent_span_id.to_string()),
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind).to_lowercase(),
+        "start_unix_nanos": nanos(span.start_time),
+        "end_unix_nanos": nanos(span.end_time),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::RequestIds;
+    use crate::tls::testing;
+    use actix_web::{test, web, App};
+
+    async fn work() -> &'static str {
+        async {}.instrument(tracing::info_span!("child work")).await;
+        "done"
+    }
+
+    #[actix_web::test]
+    async fn test_spans_are_exported_with_parent_trace() {
+        let path = testing::temp_dir("telemetry").join("spans.jsonl");
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(FileExporter::create(&path).unwrap())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestIds)
+                .wrap(Tracing)
+                .route("/work/{id}", web::get().to(work)),
+        )
+        .await;
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = test::TestRequest::get()
+            .uri("/work/1")
+            .insert_header(("traceparent", format!("00-{}-00f067aa0ba902b7-01", trace_id)))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let traceparent = res.headers().get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with(&format!("00-{}-", trace_id)), "{}", traceparent);
+
+        provider.force_flush();
+        let spans: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let root = spans.iter().find(|span| span["name"] == "GET /work/{id}").unwrap();
+        let child = spans.iter().find(|span| span["name"] == "child work").unwrap();
+
+        assert_eq!(root["trace_id"], trace_id);
+        assert_eq!(root["parent_span_id"], "00f067aa0ba902b7");
+        assert_eq!(root["kind"], "server");
+        assert_eq!(root["attributes"]["http.route"], "/work/{id}");
+        assert_eq!(root["attributes"]["http.status_code"], "200");
+        assert!(root["attributes"]["request_id"].is_string());
+        assert_eq!(child["trace_id"], trace_id);
+        assert_eq!(child["parent_span_id"], root["span_id"]);
+    }
+}
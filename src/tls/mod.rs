@@ -0,0 +1,542 @@
+use actix_http::Request;
+use actix_service::IntoServiceFactory;
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, Extensions, Response, Service, ServiceFactory};
+use actix_web::{Error, HttpServer};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use x509_parser::extensions::GeneralName;
+
+use std::any::Any;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::{fmt, fs, io, net};
+
+use crate::routes::extractors::PeerIdentity;
+use crate::settings::{TlsBackend, TlsSettings};
+
+#[cfg(feature = "openssl")]
+mod openssl;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+compile_error!("enable at least one TLS backend: the `openssl` or `rustls` feature");
+
+#[derive(Debug, derive_more::Display)]
+pub enum TlsError {
+    #[display(fmt = "cannot create TLS acceptor: {}", _0)]
+    Acceptor(String),
+    #[display(fmt = "cannot load private key {}: {}", "_0.display()", _1)]
+    Key(PathBuf, String),
+    #[display(fmt = "cannot load certificate chain {}: {}", "_0.display()", _1)]
+    Cert(PathBuf, String),
+    #[display(fmt = "cannot load client CA bundle {}: {}", "_0.display()", _1)]
+    ClientCa(PathBuf, String),
+}
+
+impl std::error::Error for TlsError {}
+
+pub enum Acceptor {
+    #[cfg(feature = "openssl")]
+    Openssl(::openssl::ssl::SslAcceptorBuilder),
+    #[cfg(feature = "rustls")]
+    Rustls(::rustls::ServerConfig),
+}
+
+impl Acceptor {
+    pub fn listen<F, I, S, B>(
+        self,
+        server: HttpServer<F, I, S, B>,
+        listener: net::TcpListener,
+    ) -> io::Result<HttpServer<F, I, S, B>>
+    where
+        F: Fn() -> I + Send + Clone + 'static,
+        I: IntoServiceFactory<S, Request>,
+        S: ServiceFactory<Request, Config = AppConfig> + 'static,
+        S::Error: Into<Error> + 'static,
+        S::InitError: fmt::Debug,
+        S::Response: Into<Response<B>> + 'static,
+        <S::Service as Service<Request>>::Future: 'static,
+        S::Service: 'static,
+        B: MessageBody + 'static,
+    {
+        match self {
+            #[cfg(feature = "openssl")]
+            Acceptor::Openssl(builder) => server.listen_openssl(listener, builder),
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(config) => server.listen_rustls(listener, config),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Backend {
+    #[cfg(feature = "openssl")]
+    Openssl(Arc<std::sync::RwLock<::openssl::ssl::SslContext>>),
+    #[cfg(feature = "rustls")]
+    Rustls(Arc<rustls::CertResolver>),
+}
+
+// Holds the certificate used for new handshakes. Connections that are already
+// established keep the certificate they were accepted with.
+#[derive(Clone)]
+pub struct CertReloader {
+    settings: TlsSettings,
+    backend: Backend,
+}
+
+impl CertReloader {
+    pub fn new(settings: &TlsSettings) -> Result<Self, TlsError> {
+        let backend = match settings.backend {
+            #[cfg(feature = "openssl")]
+            TlsBackend::Openssl => Backend::Openssl(Arc::new(std::sync::RwLock::new(openssl::context(settings)?))),
+            #[cfg(feature = "rustls")]
+            TlsBackend::Rustls => Backend::Rustls(Arc::new(rustls::CertResolver::new(settings)?)),
+            #[allow(unreachable_patterns)]
+            backend => return Err(TlsError::Acceptor(format!("{} support is not compiled in", backend))),
+        };
+
+        Ok(CertReloader {
+            settings: settings.clone(),
+            backend,
+        })
+    }
+
+    pub fn acceptor(&self) -> Result<Acceptor, TlsError> {
+        match &self.backend {
+            #[cfg(feature = "openssl")]
+            Backend::Openssl(current) => Ok(Acceptor::Openssl(openssl::reloading_acceptor(
+                &self.settings,
+                Arc::clone(current),
+            )?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls(resolver) => Ok(Acceptor::Rustls(rustls::server_config(
+                &self.settings,
+                Arc::clone(resolver),
+            )?)),
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), TlsError> {
+        match &self.backend {
+            #[cfg(feature = "openssl")]
+            Backend::Openssl(current) => {
+                let context = openssl::context(&self.settings)?;
+                *current.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = context;
+                Ok(())
+            }
+            #[cfg(feature = "rustls")]
+            Backend::Rustls(resolver) => resolver.reload(&self.settings),
+        }
+    }
+
+    fn reload_and_log(&self, reason: &str) {
+        match self.reload() {
+            Ok(()) => info!("reloaded TLS certificate ({})", reason),
+            Err(err) => error!("TLS reload failed, keeping the previous certificate: {}", err),
+        }
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = fs::metadata(&self.settings.cert).and_then(|meta| meta.modified()).ok()?;
+        let key = fs::metadata(&self.settings.key).and_then(|meta| meta.modified()).ok()?;
+        Some((cert, key))
+    }
+
+    // Reloads on SIGHUP and, when `tls.reload_interval` is set, whenever the PEM files change.
+    pub fn spawn_watcher(self) {
+        #[cfg(unix)]
+        {
+            let reloader = self.clone();
+            actix_web::rt::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut hangup = match signal(SignalKind::hangup()) {
+                    Ok(hangup) => hangup,
+                    Err(err) => return error!("cannot listen for SIGHUP: {}", err),
+                };
+                while hangup.recv().await.is_some() {
+                    reloader.reload_and_log("SIGHUP");
+                }
+            });
+        }
+
+        if self.settings.reload_interval == 0 {
+            return;
+        }
+        actix_web::rt::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.settings.reload_interval));
+            let mut last = self.modified();
+
+            loop {
+                interval.tick().await;
+                let modified = self.modified();
+                if modified.is_some() && modified != last {
+                    last = modified;
+                    self.reload_and_log("files changed");
+                }
+            }
+        });
+    }
+
+    // The certificate currently offered to new handshakes, which may be older than
+    // the PEM file on disk if the last reload failed.
+    pub fn certificate_der(&self) -> Option<Vec<u8>> {
+        match &self.backend {
+            #[cfg(feature = "openssl")]
+            Backend::Openssl(current) => {
+                openssl::certificate_der(&current.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+            }
+            #[cfg(feature = "rustls")]
+            Backend::Rustls(resolver) => resolver.certificate_der(),
+        }
+    }
+}
+
+// Negotiated protocol of a TLS connection, e.g. "TLSv1.3".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsVersion(pub &'static str);
+
+// `HttpServer::on_connect` hook that stores the negotiated TLS version and the
+// verified client certificate as connection data.
+pub fn on_connect(conn: &dyn Any, data: &mut Extensions) {
+    let version: Option<&'static str> = None;
+    #[cfg(feature = "openssl")]
+    let version = version.or_else(|| openssl::protocol_version(conn));
+    #[cfg(feature = "rustls")]
+    let version = version.or_else(|| rustls::protocol_version(conn));
+
+    if let Some(version) = version {
+        data.insert(TlsVersion(version));
+    }
+
+    let der: Option<Vec<u8>> = None;
+    #[cfg(feature = "openssl")]
+    let der = der.or_else(|| openssl::peer_certificate(conn));
+    #[cfg(feature = "rustls")]
+    let der = der.or_else(|| rustls::peer_certificate(conn));
+
+    if let Some(identity) = der.as_deref().and_then(identity) {
+        data.insert(identity);
+    }
+}
+
+pub fn identity(der: &[u8]) -> Option<PeerIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok())
+        .map(String::from);
+
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                GeneralName::IPAddress(ip) => Some(format!("IP:{}", format_ip(ip))),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let fingerprint = Sha256::digest(der).iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    Some(PeerIdentity {
+        common_name,
+        subject_alt_names,
+        fingerprint,
+    })
+}
+
+pub fn expires_at(der: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let not_after = u64::try_from(cert.validity().not_after.timestamp()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(not_after))
+}
+
+fn format_ip(ip: &[u8]) -> String {
+    match <[u8; 4]>::try_from(ip) {
+        Ok(v4) => net::Ipv4Addr::from(v4).to_string(),
+        Err(_) => match <[u8; 16]>::try_from(ip) {
+            Ok(v6) => net::Ipv6Addr::from(v6).to_string(),
+            Err(_) => String::from("invalid"),
+        },
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+
+    use std::path::{Path, PathBuf};
+
+    pub struct Pki {
+        pub ca: Certificate,
+    }
+
+    impl Pki {
+        pub fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new());
+            params.distinguished_name.push(DnType::CommonName, "test-ca");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+            Pki {
+                ca: Certificate::from_params(params).unwrap(),
+            }
+        }
+
+        // Returns a (certificate, private key) pair in PEM format signed by the test CA.
+        pub fn issue(&self, common_name: &str) -> (String, String) {
+            let mut params = CertificateParams::new(vec![common_name.to_owned()]);
+            params.distinguished_name.push(DnType::CommonName, common_name);
+            let cert = Certificate::from_params(params).unwrap();
+
+            (cert.serialize_pem_with_signer(&self.ca).unwrap(), cert.serialize_private_key_pem())
+        }
+
+        pub fn write_ca(&self, dir: &Path) -> PathBuf {
+            let path = dir.join("ca.pem");
+            std::fs::write(&path, self.ca.serialize_pem().unwrap()).unwrap();
+            path
+        }
+
+        pub fn write_pair(&self, dir: &Path, common_name: &str) -> String {
+            let (cert, key) = self.issue(common_name);
+            std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+            std::fs::write(dir.join("key.pem"), key).unwrap();
+            cert
+        }
+    }
+
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("actix-web-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub fn pem_to_der(pem: &str) -> Vec<u8> {
+        x509_parser::pem::parse_x509_pem(pem.as_bytes()).unwrap().1.contents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use crate::settings::ClientAuth;
+    use actix_web::{dev::ServerHandle, App};
+    use testing::Pki;
+
+    use std::io::{Read, Write};
+
+    fn backends() -> Vec<TlsBackend> {
+        [TlsBackend::Openssl, TlsBackend::Rustls]
+            .into_iter()
+            .filter(|backend| backend.is_compiled_in())
+            .collect()
+    }
+
+    fn settings(dir: &std::path::Path, backend: TlsBackend) -> TlsSettings {
+        TlsSettings {
+            backend,
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            ..TlsSettings::default()
+        }
+    }
+
+    async fn serve(certs: &CertReloader) -> (ServerHandle, u16) {
+        let app = || {
+            App::new()
+                .configure(routes::application_routes)
+                .configure(routes::extractor_routes)
+        };
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(app).workers(1).on_connect(on_connect);
+        let server = certs.acceptor().unwrap().listen(server, listener).unwrap().run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        (handle, port)
+    }
+
+    struct AcceptAnyServer;
+
+    impl ::rustls::client::ServerCertVerifier for AcceptAnyServer {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &::rustls::Certificate,
+            _intermediates: &[::rustls::Certificate],
+            _server_name: &::rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<::rustls::client::ServerCertVerified, ::rustls::Error> {
+            Ok(::rustls::client::ServerCertVerified::assertion())
+        }
+    }
+
+    // Plain blocking rustls client, used against both backends. Returns the
+    // server certificate's common name and the response body.
+    async fn get(port: u16, path: &str, client_cert: Option<(String, String)>) -> io::Result<(String, String)> {
+        let path = path.to_owned();
+        actix_web::rt::task::spawn_blocking(move || {
+            let builder = ::rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyServer));
+            let config = match client_cert {
+                Some((cert, key)) => {
+                    let key = rustls_pemfile_key(&key);
+                    builder
+                        .with_single_cert(vec![::rustls::Certificate(testing::pem_to_der(&cert))], key)
+                        .unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            let conn = ::rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+            let sock = net::TcpStream::connect(("127.0.0.1", port))?;
+            let mut tls = ::rustls::StreamOwned::new(conn, sock);
+
+            write!(tls, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)?;
+            let mut response = Vec::new();
+            match tls.read_to_end(&mut response) {
+                Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err),
+                _ => {}
+            }
+
+            let server_cert = &tls.conn.peer_certificates().unwrap()[0];
+            let common_name = identity(&server_cert.0).unwrap().common_name.unwrap();
+            let response = String::from_utf8_lossy(&response);
+            let body = response.split("\r\n\r\n").nth(1).unwrap_or_default().to_owned();
+            Ok((common_name, body))
+        })
+        .await
+        .unwrap()
+    }
+
+    fn rustls_pemfile_key(pem: &str) -> ::rustls::PrivateKey {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem.as_bytes()).unwrap();
+        ::rustls::PrivateKey(pem.contents)
+    }
+
+    #[actix_web::test]
+    async fn test_backends_serve_and_reload() {
+        for backend in backends() {
+            let dir = testing::temp_dir(&format!("tls-serve-{}", backend));
+            let pki = Pki::new();
+            pki.write_pair(&dir, "first.test");
+
+            let certs = CertReloader::new(&settings(&dir, backend)).unwrap();
+            let (handle, port) = serve(&certs).await;
+
+            let (common_name, body) = get(port, "/hello", None).await.unwrap();
+            assert_eq!(common_name, "first.test", "{}", backend);
+            assert_eq!(body, "Hello world!", "{}", backend);
+
+            let second = pki.write_pair(&dir, "second.test");
+            certs.reload().unwrap();
+            assert_eq!(certs.certificate_der(), Some(testing::pem_to_der(&second)));
+            let (common_name, _) = get(port, "/hello", None).await.unwrap();
+            assert_eq!(common_name, "second.test", "{}", backend);
+
+            // a broken key must not replace the working certificate
+            fs::write(dir.join("key.pem"), b"not a key").unwrap();
+            assert!(certs.reload().is_err());
+            assert_eq!(certs.certificate_der(), Some(testing::pem_to_der(&second)));
+
+            handle.stop(false).await;
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_backends_mutual_tls() {
+        for backend in backends() {
+            let dir = testing::temp_dir(&format!("tls-mutual-{}", backend));
+            let pki = Pki::new();
+            pki.write_pair(&dir, "localhost");
+
+            let settings = TlsSettings {
+                client_auth: ClientAuth::Required,
+                client_ca: Some(pki.write_ca(&dir)),
+                ..settings(&dir, backend)
+            };
+            let certs = CertReloader::new(&settings).unwrap();
+            let (handle, port) = serve(&certs).await;
+
+            let client = pki.issue("alice");
+            let (_, body) = get(port, "/client-cert", Some(client.clone())).await.unwrap();
+            let identity: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(identity["common_name"], "alice", "{}", backend);
+            assert_eq!(identity["subject_alt_names"][0], "DNS:alice", "{}", backend);
+
+            assert!(get(port, "/client-cert", None).await.is_err(), "{}", backend);
+
+            let stranger = Pki::new().issue("mallory");
+            assert!(get(port, "/client-cert", Some(stranger)).await.is_err(), "{}", backend);
+
+            handle.stop(false).await;
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_mismatched_key_is_rejected() {
+        for backend in backends() {
+            let dir = testing::temp_dir(&format!("tls-mismatch-{}", backend));
+            let pki = Pki::new();
+            let first = pki.write_pair(&dir, "first.test");
+            let certs = CertReloader::new(&settings(&dir, backend)).unwrap();
+
+            // the certificate has been rotated but the key hasn't yet
+            let (cert, _) = pki.issue("second.test");
+            fs::write(dir.join("cert.pem"), cert).unwrap();
+            assert!(certs.reload().is_err(), "{}", backend);
+            assert_eq!(certs.certificate_der(), Some(testing::pem_to_der(&first)), "{}", backend);
+            assert!(CertReloader::new(&settings(&dir, backend)).is_err(), "{}", backend);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_identity_from_certificate() {
+        let (cert, _) = Pki::new().issue("client.test");
+        let der = testing::pem_to_der(&cert);
+        let identity = identity(&der).unwrap();
+
+        assert_eq!(identity.common_name.as_deref(), Some("client.test"));
+        assert_eq!(identity.subject_alt_names, vec!["DNS:client.test"]);
+        assert_eq!(identity.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn test_missing_client_ca_is_reported() {
+        for backend in backends() {
+            let dir = testing::temp_dir(&format!("tls-client-ca-{}", backend));
+            Pki::new().write_pair(&dir, "localhost");
+
+            let settings = TlsSettings {
+                client_auth: ClientAuth::Required,
+                client_ca: Some(dir.join("missing.pem")),
+                ..settings(&dir, backend)
+            };
+            let certs = CertReloader::new(&settings);
+            let acceptor = certs.and_then(|certs| certs.acceptor());
+            assert!(matches!(acceptor, Err(TlsError::ClientCa(..))), "{}", backend);
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::rt::net::TcpStream;
+use openssl::ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+
+use std::any::Any;
+use std::sync::{Arc, RwLock};
+
+use super::TlsError;
+use crate::settings::{ClientAuth, TlsSettings};
+
+pub fn acceptor(settings: &TlsSettings) -> Result<SslAcceptorBuilder, TlsError> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|err| TlsError::Acceptor(err.to_string()))?;
+    builder
+        .set_private_key_file(&settings.key, SslFiletype::PEM)
+        .map_err(|err| TlsError::Key(settings.key.clone(), err.to_string()))?;
+    builder
+        .set_certificate_chain_file(&settings.cert)
+        .map_err(|err| TlsError::Cert(settings.cert.clone(), err.to_string()))?;
+    builder
+        .check_private_key()
+        .map_err(|err| TlsError::Key(settings.key.clone(), err.to_string()))?;
+
+    if let Some(client_ca) = &settings.client_ca {
+        let ca_error = |err: openssl::error::ErrorStack| TlsError::ClientCa(client_ca.clone(), err.to_string());
+        let mode = match settings.client_auth {
+            ClientAuth::None => return Ok(builder),
+            ClientAuth::Optional => SslVerifyMode::PEER,
+            ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        };
+        builder.set_ca_file(client_ca).map_err(ca_error)?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca).map_err(ca_error)?);
+        builder.set_verify(mode);
+    }
+
+    Ok(builder)
+}
+
+pub fn context(settings: &TlsSettings) -> Result<SslContext, TlsError> {
+    Ok(acceptor(settings)?.build().into_context())
+}
+
+// The servername callback runs for every ClientHello, with or without SNI, so
+// each new handshake picks up whatever context is current at that moment.
+pub fn reloading_acceptor(
+    settings: &TlsSettings,
+    current: Arc<RwLock<SslContext>>,
+) -> Result<SslAcceptorBuilder, TlsError> {
+    let mut builder = acceptor(settings)?;
+    builder.set_servername_callback(move |ssl, _alert| {
+        let context = current.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        ssl.set_ssl_context(&context).map_err(|_| SniError::ALERT_FATAL)
+    });
+
+    Ok(builder)
+}
+
+pub fn protocol_version(conn: &dyn Any) -> Option<&'static str> {
+    let stream = conn.downcast_ref::<TlsStream<TcpStream>>()?;
+    Some(stream.ssl().version_str())
+}
+
+pub fn peer_certificate(conn: &dyn Any) -> Option<Vec<u8>> {
+    let stream = conn.downcast_ref::<TlsStream<TcpStream>>()?;
+    stream.ssl().peer_certificate()?.to_der().ok()
+}
+
+pub fn certificate_der(context: &SslContext) -> Option<Vec<u8>> {
+    context.certificate()?.to_der().ok()
+}
@@ -0,0 +1,144 @@
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::rt::net::TcpStream;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ProtocolVersion, RootCertStore, ServerConfig, SignatureScheme};
+use rustls_pemfile::Item;
+
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use super::TlsError;
+use crate::settings::{ClientAuth, TlsSettings};
+
+// Serves whichever certificate was loaded last; `reload` swaps it for new handshakes.
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(settings: &TlsSettings) -> Result<Self, TlsError> {
+        Ok(CertResolver {
+            current: RwLock::new(Arc::new(certified_key(settings)?)),
+        })
+    }
+
+    pub fn reload(&self, settings: &TlsSettings) -> Result<(), TlsError> {
+        let key = Arc::new(certified_key(settings)?);
+        *self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = key;
+        Ok(())
+    }
+
+    pub fn certificate_der(&self) -> Option<Vec<u8>> {
+        let current = self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        current.cert.first().map(|cert| cert.0.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let current = self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Some(Arc::clone(&current))
+    }
+}
+
+pub fn server_config(settings: &TlsSettings, resolver: Arc<CertResolver>) -> Result<ServerConfig, TlsError> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match (&settings.client_ca, settings.client_auth) {
+        (Some(client_ca), ClientAuth::Optional) => {
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots(client_ca)?))
+        }
+        (Some(client_ca), ClientAuth::Required) => {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots(client_ca)?))
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+pub fn protocol_version(conn: &dyn Any) -> Option<&'static str> {
+    let stream = conn.downcast_ref::<TlsStream<TcpStream>>()?;
+    match stream.get_ref().1.protocol_version()? {
+        ProtocolVersion::TLSv1_2 => Some("TLSv1.2"),
+        ProtocolVersion::TLSv1_3 => Some("TLSv1.3"),
+        _ => Some("unknown"),
+    }
+}
+
+pub fn peer_certificate(conn: &dyn Any) -> Option<Vec<u8>> {
+    let stream = conn.downcast_ref::<TlsStream<TcpStream>>()?;
+    let certs = stream.get_ref().1.peer_certificates()?;
+    certs.first().map(|cert| cert.0.clone())
+}
+
+fn certified_key(settings: &TlsSettings) -> Result<CertifiedKey, TlsError> {
+    let certs = read_certs(&settings.cert).map_err(|err| TlsError::Cert(settings.cert.clone(), err))?;
+    let key = read_key(&settings.key).map_err(|err| TlsError::Key(settings.key.clone(), err))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| TlsError::Key(settings.key.clone(), String::from("unsupported private key type")))?;
+    check_key_matches(&certs[0], key.as_ref()).map_err(|err| TlsError::Key(settings.key.clone(), err))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// rustls doesn't check the pair itself (openssl's `check_private_key` does), so
+// sign a probe with the key and verify it against the leaf certificate. A key and
+// certificate caught mid-rotation are rejected instead of failing every handshake.
+fn check_key_matches(leaf: &Certificate, key: &dyn sign::SigningKey) -> Result<(), String> {
+    const PROBE: &[u8] = b"certificate and private key match";
+    let schemes = [
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ];
+    let signer = key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| String::from("unsupported private key type"))?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let signature = signer.sign(PROBE).map_err(|err| err.to_string())?;
+    let cert = webpki::EndEntityCert::try_from(leaf.0.as_slice()).map_err(|err| err.to_string())?;
+    cert.verify_signature(algorithm, PROBE, &signature)
+        .map_err(|_| String::from("private key does not match the certificate"))
+}
+
+fn roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let ca_error = |err: String| TlsError::ClientCa(path.to_owned(), err);
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path).map_err(ca_error)? {
+        roots.add(&cert).map_err(|err| ca_error(err.to_string()))?;
+    }
+
+    Ok(roots)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| err.to_string())?);
+    let certs = rustls_pemfile::certs(&mut reader).map_err(|err| err.to_string())?;
+    if certs.is_empty() {
+        return Err(String::from("no PEM certificates found"));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| err.to_string())?);
+    for item in rustls_pemfile::read_all(&mut reader).map_err(|err| err.to_string())? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(String::from("no unencrypted PEM private key found"))
+}
@@ -0,0 +1,354 @@
+use actix_web::{body, error, http, HttpResponse};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::problem::Problem;
+use crate::settings::{UserStoreKind, UsersSettings};
+use crate::validation::not_blank;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+    pub email: String,
+}
+
+// Request body of create and update.
+#[derive(Debug, Clone, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct NewUser {
+    #[validate(length(max = 100), custom = "not_blank")]
+    pub name: String,
+    #[validate(email)]
+    pub email: String,
+}
+
+// `name` matches case-insensitive substrings, `email` whole addresses.
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    // Matches across all pages.
+    pub total: u64,
+}
+
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum UserError {
+    #[from(ignore)]
+    #[display(fmt = "user {} not found", _0)]
+    NotFound(u64),
+    #[from(ignore)]
+    #[display(fmt = "a user with email {} already exists", _0)]
+    EmailTaken(String),
+    #[display(fmt = "user database error: {}", _0)]
+    Sqlite(rusqlite::Error),
+}
+
+impl std::error::Error for UserError {}
+
+impl error::ResponseError for UserError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            UserError::NotFound(_) => http::StatusCode::NOT_FOUND,
+            UserError::EmailTaken(_) => http::StatusCode::CONFLICT,
+            UserError::Sqlite(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Database errors are logged rather than shown to the client.
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        let message = match self {
+            UserError::Sqlite(_) => {
+                log::error!("{}", self);
+                String::from("users are unavailable")
+            }
+            _ => self.to_string(),
+        };
+        Problem::new(self.status_code()).with_detail(message).response()
+    }
+}
+
+// Storage behind the `/users` resource. Calls may block, so handlers run them
+// through `request_id::block`.
+pub trait UserRepository: Send + Sync {
+    fn list(&self, filter: &UserFilter) -> Result<UserPage, UserError>;
+    fn get(&self, id: u64) -> Result<User, UserError>;
+    fn create(&self, user: NewUser) -> Result<User, UserError>;
+    fn update(&self, id: u64, user: NewUser) -> Result<User, UserError>;
+    fn delete(&self, id: u64) -> Result<(), UserError>;
+}
+
+pub fn open(settings: &UsersSettings) -> Result<Arc<dyn UserRepository>, UserError> {
+    Ok(match settings.store {
+        UserStoreKind::Memory => Arc::new(MemoryUsers::default()),
+        UserStoreKind::Sqlite => Arc::new(SqliteUsers::open(&settings.database)?),
+    })
+}
+
+#[derive(Default)]
+pub struct MemoryUsers {
+    inner: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: BTreeMap<u64, User>,
+    last_id: u64,
+}
+
+impl MemoryState {
+    fn email_taken(&self, email: &str, except: Option<u64>) -> bool {
+        self.users
+            .values()
+            .any(|user| Some(user.id) != except && user.email.eq_ignore_ascii_case(email))
+    }
+}
+
+impl MemoryUsers {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl UserRepository for MemoryUsers {
+    fn list(&self, filter: &UserFilter) -> Result<UserPage, UserError> {
+        let state = self.state();
+        let name = filter.name.as_ref().map(|name| name.to_lowercase());
+        let matches: Vec<&User> = state
+            .users
+            .values()
+            .filter(|user| name.as_ref().is_none_or(|name| user.name.to_lowercase().contains(name)))
+            .filter(|user| filter.email.as_ref().is_none_or(|email| user.email.eq_ignore_ascii_case(email)))
+            .collect();
+
+        Ok(UserPage {
+            total: matches.len() as u64,
+            users: matches
+                .into_iter()
+                .skip(filter.offset as usize)
+                .take(filter.limit as usize)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    fn get(&self, id: u64) -> Result<User, UserError> {
+        self.state().users.get(&id).cloned().ok_or(UserError::NotFound(id))
+    }
+
+    fn create(&self, user: NewUser) -> Result<User, UserError> {
+        let mut state = self.state();
+        if state.email_taken(&user.email, None) {
+            return Err(UserError::EmailTaken(user.email));
+        }
+        state.last_id += 1;
+        let user = User {
+            id: state.last_id,
+            name: user.name,
+            email: user.email,
+        };
+        state.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    fn update(&self, id: u64, user: NewUser) -> Result<User, UserError> {
+        let mut state = self.state();
+        if !state.users.contains_key(&id) {
+            return Err(UserError::NotFound(id));
+        }
+        if state.email_taken(&user.email, Some(id)) {
+            return Err(UserError::EmailTaken(user.email));
+        }
+        let user = User {
+            id,
+            name: user.name,
+            email: user.email,
+        };
+        state.users.insert(id, user.clone());
+        Ok(user)
+    }
+
+    fn delete(&self, id: u64) -> Result<(), UserError> {
+        self.state().users.remove(&id).map(drop).ok_or(UserError::NotFound(id))
+    }
+}
+
+pub struct SqliteUsers {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteUsers {
+    pub fn open(path: &Path) -> Result<Self, UserError> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                email TEXT NOT NULL UNIQUE COLLATE NOCASE
+            )",
+            [],
+        )?;
+        Ok(SqliteUsers { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn user(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get::<_, i64>(0)? as u64,
+        name: row.get(1)?,
+        email: row.get(2)?,
+    })
+}
+
+// Turns a UNIQUE violation on `users.email` into a conflict.
+fn email_taken(email: String) -> impl FnOnce(rusqlite::Error) -> UserError {
+    move |err| match err.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => UserError::EmailTaken(email),
+        _ => UserError::Sqlite(err),
+    }
+}
+
+const FILTER: &str = "(?1 IS NULL OR instr(lower(name), lower(?1)) > 0) AND (?2 IS NULL OR email = ?2)";
+
+impl UserRepository for SqliteUsers {
+    fn list(&self, filter: &UserFilter) -> Result<UserPage, UserError> {
+        let conn = self.conn();
+        let total: i64 = conn.query_row(
+            &format!("SELECT count(*) FROM users WHERE {}", FILTER),
+            params![filter.name, filter.email],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, name, email FROM users WHERE {} ORDER BY id LIMIT ?3 OFFSET ?4",
+            FILTER
+        ))?;
+        let users = stmt
+            .query_map(
+                params![filter.name, filter.email, filter.limit as i64, filter.offset as i64],
+                user,
+            )?
+            .collect::<Result<_, _>>()?;
+
+        Ok(UserPage {
+            users,
+            total: total as u64,
+        })
+    }
+
+    fn get(&self, id: u64) -> Result<User, UserError> {
+        self.conn()
+            .query_row("SELECT id, name, email FROM users WHERE id = ?1", [id as i64], user)
+            .optional()?
+            .ok_or(UserError::NotFound(id))
+    }
+
+    fn create(&self, new: NewUser) -> Result<User, UserError> {
+        self.conn()
+            .query_row(
+                "INSERT INTO users (name, email) VALUES (?1, ?2) RETURNING id, name, email",
+                params![new.name, new.email],
+                user,
+            )
+            .map_err(email_taken(new.email))
+    }
+
+    fn update(&self, id: u64, new: NewUser) -> Result<User, UserError> {
+        self.conn()
+            .query_row(
+                "UPDATE users SET name = ?2, email = ?3 WHERE id = ?1 RETURNING id, name, email",
+                params![id as i64, new.name, new.email],
+                user,
+            )
+            .optional()
+            .map_err(email_taken(new.email))?
+            .ok_or(UserError::NotFound(id))
+    }
+
+    fn delete(&self, id: u64) -> Result<(), UserError> {
+        match self.conn().execute("DELETE FROM users WHERE id = ?1", [id as i64])? {
+            0 => Err(UserError::NotFound(id)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::testing;
+
+    fn new_user(name: &str, email: &str) -> NewUser {
+        NewUser {
+            name: name.to_owned(),
+            email: email.to_owned(),
+        }
+    }
+
+    // Both repositories have to behave the same way.
+    fn exercise(repo: &dyn UserRepository) {
+        let alice = repo.create(new_user("Alice", "alice@example.com")).unwrap();
+        let bob = repo.create(new_user("Bob", "bob@example.com")).unwrap();
+        repo.create(new_user("Alicia", "alicia@example.com")).unwrap();
+        assert!(matches!(
+            repo.create(new_user("Other", "ALICE@example.com")),
+            Err(UserError::EmailTaken(_))
+        ));
+
+        let filter = UserFilter {
+            name: Some(String::from("ALI")),
+            limit: 1,
+            ..UserFilter::default()
+        };
+        let page = repo.list(&filter).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.users, vec![alice.clone()]);
+        let page = repo.list(&UserFilter { offset: 1, ..filter }).unwrap();
+        assert_eq!(page.users[0].name, "Alicia");
+        let filter = UserFilter {
+            email: Some(String::from("bob@example.com")),
+            limit: 10,
+            ..UserFilter::default()
+        };
+        assert_eq!(repo.list(&filter).unwrap().users, vec![bob.clone()]);
+
+        let updated = repo.update(bob.id, new_user("Robert", "bob@example.com")).unwrap();
+        assert_eq!(repo.get(bob.id).unwrap(), updated);
+        assert!(matches!(
+            repo.update(bob.id, new_user("Robert", "alice@example.com")),
+            Err(UserError::EmailTaken(_))
+        ));
+        assert!(matches!(repo.update(99, new_user("Nobody", "n@example.com")), Err(UserError::NotFound(99))));
+
+        repo.delete(alice.id).unwrap();
+        assert!(matches!(repo.get(alice.id), Err(UserError::NotFound(_))));
+        assert!(matches!(repo.delete(alice.id), Err(UserError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_memory_repository() {
+        exercise(&MemoryUsers::default());
+    }
+
+    #[test]
+    fn test_sqlite_repository() {
+        let path = testing::temp_dir("users-sqlite").join("users.db");
+        let _ = std::fs::remove_file(&path);
+        exercise(&SqliteUsers::open(&path).unwrap());
+    }
+}
@@ -0,0 +1,216 @@
+use actix_web::{body, dev, error, http, Error, FromRequest, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use regex::Regex;
+use serde::Serialize;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+use std::ops::Deref;
+use std::sync::LazyLock;
+
+use crate::problem::Problem;
+
+// Letters, digits and underscores; shared by the username fields of the extractor examples.
+pub static USERNAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_]+$").unwrap());
+
+// Wraps an extractor whose value derives `Validate` and checks its rules once the
+// value is extracted, so `Validated<web::Json<T>>`, `Validated<web::Form<T>>`,
+// `Validated<web::Query<T>>` and `Validated<web::Path<T>>` only reach the handler
+// with valid data. Derefs through to `T` like the wrapped extractor.
+pub struct Validated<E>(pub E);
+
+impl<E> Validated<E> {
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<E> Deref for Validated<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E> FromRequest for Validated<E>
+where
+    E: FromRequest + Deref + 'static,
+    E::Target: Validate,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let extract = E::from_request(req, payload);
+        Box::pin(async move {
+            let value = extract.await.map_err(Into::into)?;
+            value.validate().map_err(ValidationError::from)?;
+            Ok(Validated(value))
+        })
+    }
+}
+
+// Custom rule for free-text fields: rejects values that are only whitespace.
+pub fn not_blank(value: &str) -> Result<(), validator::ValidationError> {
+    match value.trim().is_empty() {
+        true => Err(validator::ValidationError::new("not_blank")),
+        false => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    // Dotted path of the field, with `[i]` for list items, e.g. `tags[1].name`.
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+// Every rule that failed, rendered as a 422 listing all of them.
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "validation failed for {} field(s)", "fields.len()")]
+pub struct ValidationError {
+    pub fields: Vec<FieldError>,
+}
+
+impl std::error::Error for ValidationError {}
+
+impl error::ResponseError for ValidationError {
+    fn status_code(&self) -> http::StatusCode {
+        http::StatusCode::UNPROCESSABLE_ENTITY
+    }
+
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        Problem::new(self.status_code())
+            .with_type("/problems/validation", "Validation failed")
+            .with_detail(self)
+            .with_extension("fields", &self.fields)
+            .response()
+    }
+}
+
+impl From<ValidationErrors> for ValidationError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        flatten(&errors, "", &mut fields);
+        ValidationError { fields }
+    }
+}
+
+fn flatten(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    // `ValidationErrors` is a HashMap; sort so responses are stable.
+    let mut kinds: Vec<_> = errors.errors().iter().collect();
+    kinds.sort_by_key(|(field, _)| *field);
+
+    for (field, kind) in kinds {
+        let path = match prefix {
+            "" => field.to_string(),
+            _ => format!("{}.{}", prefix, field),
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => out.extend(errors.iter().map(|err| FieldError {
+                field: path.clone(),
+                code: err.code.to_string(),
+                message: describe(err),
+            })),
+            ValidationErrorsKind::Struct(errors) => flatten(errors, &path, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    flatten(errors, &format!("{}[{}]", path, index), out);
+                }
+            }
+        }
+    }
+}
+
+// The rule's own message if it has one, otherwise a sentence built from its code
+// and parameters.
+fn describe(err: &validator::ValidationError) -> String {
+    if let Some(message) = &err.message {
+        return message.to_string();
+    }
+    // Range bounds are stored as floats; show whole numbers without the `.0`.
+    let param = |name: &str| {
+        err.params.get(name).map(|value| match value.as_f64() {
+            Some(number) if number.fract() == 0.0 => (number as i64).to_string(),
+            _ => value.to_string(),
+        })
+    };
+    match (err.code.as_ref(), param("min"), param("max")) {
+        ("length", Some(min), Some(max)) => format!("must be {} to {} characters long", min, max),
+        ("length", Some(min), None) => format!("must be at least {} characters long", min),
+        ("length", None, Some(max)) => format!("must be at most {} characters long", max),
+        ("range", Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+        ("range", Some(min), None) => format!("must be at least {}", min),
+        ("range", None, Some(max)) => format!("must be at most {}", max),
+        ("email", ..) => String::from("must be a valid email address"),
+        ("regex", ..) => String::from("has an invalid format"),
+        ("not_blank", ..) => String::from("must not be blank"),
+        (code, ..) => format!("failed the `{}` rule", code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use crate::stats::Stats;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn test_invalid_extractors_answer_422_with_every_field() {
+        let app = test::init_service(
+            App::new()
+                .app_data(routes::extractors::state(&Stats::default()))
+                .configure(routes::extractor_routes)
+                .configure(routes::url_dispatch_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post().uri("/json").set_json(json!({ "name": "   " })).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["fields"], json!([{ "field": "name", "code": "not_blank", "message": "must not be blank" }]));
+
+        let req = test::TestRequest::post().uri("/form").set_form([("username", "")]).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let codes: Vec<_> = body["fields"].as_array().unwrap().iter().map(|f| f["code"].as_str().unwrap()).collect();
+        assert_eq!(codes, ["length", "regex"]);
+
+        let req = test::TestRequest::get().uri("/query?name=").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = test::TestRequest::get().uri("/url-dispatch/v2/path/bad%20name/1").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = test::TestRequest::post().uri("/form").set_form([("username", "alice_1")]).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Welcome alice_1");
+    }
+
+    #[actix_web::test]
+    async fn test_nested_errors_are_flattened() {
+        #[derive(Validate)]
+        struct Tag {
+            #[validate(length(min = 1))]
+            name: String,
+        }
+
+        #[derive(Validate)]
+        struct Post {
+            #[validate(range(max = 10))]
+            score: u32,
+            #[validate]
+            tags: Vec<Tag>,
+        }
+
+        let post = Post {
+            score: 11,
+            tags: vec![Tag { name: "a".into() }, Tag { name: String::new() }],
+        };
+        let err = ValidationError::from(post.validate().unwrap_err());
+        let fields: Vec<_> = err.fields.iter().map(|f| (f.field.as_str(), f.message.as_str())).collect();
+        assert_eq!(fields, [("score", "must be at most 10"), ("tags[1].name", "must be at least 1 characters long")]);
+    }
+}
@@ -0,0 +1,263 @@
+use actix_files::Files;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{guard, web, Error, HttpMessage};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::fallback::RouteTable;
+use crate::routes::{self, extractors::ActiveHost};
+use crate::settings::{HostPattern, VhostSettings};
+
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "virtual host {:?} mounts unknown route group {:?}", host, group)]
+pub struct UnknownRouteGroup {
+    host: String,
+    group: String,
+}
+
+impl std::error::Error for UnknownRouteGroup {}
+
+// Serves each configured virtual host from its own route groups and static root.
+// As middleware it picks the host for a request and stores it as `ActiveHost`; the
+// host's routes are mounted in a scope guarded on that name.
+#[derive(Clone)]
+pub struct VirtualHosts {
+    config: Arc<Config>,
+}
+
+struct Config {
+    hosts: Vec<Host>,
+    default: Option<usize>,
+}
+
+struct Host {
+    name: String,
+    patterns: Vec<HostPattern>,
+    routes: Vec<routes::RouteGroup>,
+    table: Vec<routes::Route>,
+    static_root: Option<PathBuf>,
+}
+
+impl VirtualHosts {
+    pub fn from_settings(settings: &VhostSettings) -> Result<Self, UnknownRouteGroup> {
+        // Without configured hosts a single host serves every route group.
+        if settings.hosts.is_empty() {
+            let host = Host {
+                name: String::from("default"),
+                patterns: vec![HostPattern::Any],
+                routes: routes::GROUPS.iter().map(|(_, routes, _)| *routes).collect(),
+                table: routes::GROUPS.iter().flat_map(|(_, _, table)| table.iter().copied()).collect(),
+                static_root: None,
+            };
+            return Ok(VirtualHosts {
+                config: Arc::new(Config {
+                    hosts: vec![host],
+                    default: Some(0),
+                }),
+            });
+        }
+
+        let mut hosts = Vec::new();
+        for host in &settings.hosts {
+            let groups = host
+                .routes
+                .iter()
+                .map(|group| match routes::GROUPS.iter().find(|(name, _, _)| name == group) {
+                    Some(&(_, routes, table)) => Ok((routes, table)),
+                    None => Err(UnknownRouteGroup {
+                        host: host.name.clone(),
+                        group: group.clone(),
+                    }),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            hosts.push(Host {
+                name: host.name.clone(),
+                patterns: host.patterns(),
+                routes: groups.iter().map(|(routes, _)| *routes).collect(),
+                table: groups.iter().flat_map(|(_, table)| table.iter().copied()).collect(),
+                static_root: host.static_root.clone(),
+            });
+        }
+        let default = settings
+            .default
+            .as_ref()
+            .and_then(|default| hosts.iter().position(|host| &host.name == default));
+
+        Ok(VirtualHosts {
+            config: Arc::new(Config { hosts, default }),
+        })
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        for host in &self.config.hosts {
+            let name = host.name.clone();
+            let mut scope = web::scope("").guard(guard::fn_guard(move |ctx| {
+                ctx.req_data().get::<ActiveHost>().is_some_and(|active| active.name == name)
+            }));
+            for routes in &host.routes {
+                scope = scope.configure(*routes);
+            }
+            if let Some(root) = &host.static_root {
+                scope = scope.service(Files::new("/", root).index_file("index.html"));
+            }
+            cfg.service(scope);
+        }
+    }
+
+    pub fn route_table(&self) -> RouteTable {
+        RouteTable::new(self.config.hosts.iter().map(|host| (host.name.clone(), host.table.as_slice())))
+    }
+}
+
+impl Config {
+    // Hosts are tried in configuration order; the first matching pattern wins.
+    fn resolve(&self, host: &str) -> Option<&Host> {
+        self.hosts
+            .iter()
+            .find(|candidate| candidate.patterns.iter().any(|pattern| matches(pattern, host)))
+            .or_else(|| self.hosts.get(self.default?))
+    }
+}
+
+fn matches(pattern: &HostPattern, host: &str) -> bool {
+    match pattern {
+        HostPattern::Any => true,
+        HostPattern::Exact(name) => host == name,
+        HostPattern::Subdomain(domain) => host
+            .strip_suffix(domain.as_str())
+            .and_then(|sub| sub.strip_suffix('.'))
+            .is_some_and(|sub| !sub.is_empty()),
+    }
+}
+
+// Lowercases and drops the port and a trailing dot: `WWW.Example.test.:8443` -> `www.example.test`.
+fn normalize(host: &str) -> String {
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for VirtualHosts
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = VirtualHostsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(VirtualHostsMiddleware {
+            service: Rc::new(service),
+            config: Arc::clone(&self.config),
+        }))
+    }
+}
+
+pub struct VirtualHostsMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<Config>,
+}
+
+impl<S, B> Service<ServiceRequest> for VirtualHostsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // `connection_info` reflects the Forwarded header of a trusted proxy.
+        let host = normalize(req.connection_info().host());
+        if let Some(active) = self.config.resolve(&host) {
+            req.extensions_mut().insert(ActiveHost {
+                name: active.name.clone(),
+                host,
+            });
+        }
+
+        Box::pin(self.service.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use actix_web::{http, test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn test_host_matching() {
+        let pattern = |value: &str| HostPattern::Subdomain(String::from(value));
+        assert!(matches(&pattern("example.test"), "a.example.test"));
+        assert!(matches(&pattern("example.test"), "a.b.example.test"));
+        assert!(!matches(&pattern("example.test"), "example.test"));
+        assert!(!matches(&pattern("example.test"), "badexample.test"));
+
+        assert_eq!(normalize("WWW.Example.test:8443"), "www.example.test");
+        assert_eq!(normalize("example.test."), "example.test");
+        assert_eq!(normalize("[::1]:8443"), "[::1]");
+    }
+
+    #[actix_web::test]
+    async fn test_hosts_mount_their_own_routes() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [vhosts]
+            default = "main"
+
+            [[vhosts.hosts]]
+            name = "main"
+            routes = ["application", "extractors"]
+
+            [[vhosts.hosts]]
+            name = "docs"
+            patterns = ["*.docs.example.test"]
+            routes = ["extractors"]
+            static_root = "static"
+            "#,
+        )
+        .unwrap();
+        let vhosts = VirtualHosts::from_settings(&settings.vhosts).unwrap();
+        let counter = routes::application::counter(Box::<crate::counter::MemoryStore>::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(counter)
+                .wrap(vhosts.clone())
+                .configure(|cfg| vhosts.configure(cfg)),
+        )
+        .await;
+
+        let get = |host: &'static str, uri: &'static str| {
+            test::TestRequest::get().uri(uri).insert_header(("Host", host)).to_request()
+        };
+        let res = test::call_service(&app, get("API.Docs.Example.test:8443", "/")).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert!(test::read_body(res).await.starts_with(b"<!DOCTYPE html>"));
+
+        let host: Value = test::call_and_read_body_json(&app, get("api.docs.example.test", "/active-host")).await;
+        assert_eq!(host["name"], "docs");
+        assert_eq!(host["host"], "api.docs.example.test");
+
+        let body = test::call_and_read_body(&app, get("localhost:8080", "/")).await;
+        assert_eq!(body, "Hello Actix Web, Request number: 1");
+        let host: Value = test::call_and_read_body_json(&app, get("docs.example.test", "/active-host")).await;
+        assert_eq!(host["name"], "main");
+
+        let mut settings = settings;
+        settings.vhosts.hosts[1].routes.push(String::from("blog"));
+        assert!(VirtualHosts::from_settings(&settings.vhosts).is_err());
+    }
+}